@@ -0,0 +1,314 @@
+use crate::{
+    engine::{Connection, Engine, Status},
+    jack::{self, JackBackend},
+};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::SyncSender,
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Seconds the file backend keeps rendering after the event source finished, for releases.
+const TAIL: f64 = 2.;
+
+/// Something that drives the engine, asking it for audio and handing it MIDI events.
+/// Backends without MIDI input of their own rely on the engine's event source.
+pub trait AudioBackend {
+    /// Sample rate to create the engine with.
+    /// Backends whose sample rate can change send the new one to the engine themselves.
+    fn sample_rate(&self) -> f64;
+
+    /// Starts driving `engine` in the background.
+    fn start(&mut self, engine: Arc<Mutex<Engine>>);
+
+    /// Stops driving the engine and waits until it's done.
+    fn stop(&mut self);
+
+    /// Whether the backend is still driving the engine, offline backends finish on their own.
+    fn is_running(&self) -> bool;
+
+    /// Whether the backend receives MIDI events itself, besides the engine's event source.
+    fn has_event_input(&self) -> bool;
+
+    /// Number of audio channels the backend outputs, one or two.
+    fn output_channels(&self) -> usize;
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Kind {
+    /// Play through the JACK server
+    Jack,
+    /// Run the engine in real time but throw its output away
+    Null,
+    /// Render into a WAV file as fast as possible
+    File,
+}
+
+/// Which backend to use and how to set it up.
+#[derive(clap::Args, Debug)]
+#[command(about = None, long_about = None)]
+#[group(id = "backend_options")]
+pub struct Options {
+    /// Audio backend
+    #[arg(long, value_enum, default_value_t = Kind::Jack)]
+    pub backend: Kind,
+    /// WAV file the file backend renders into
+    #[arg(long, default_value = "rsynth.wav")]
+    pub output: PathBuf,
+    /// Sample rate of the null and file backends
+    #[arg(long, default_value_t = 48000)]
+    pub sample_rate: u32,
+    /// Frames the null and file backends render at once
+    #[arg(long, default_value_t = 256)]
+    pub buffer_size: usize,
+    /// Seconds the file backend renders, by default until the event source finishes
+    #[arg(long)]
+    pub length: Option<f64>,
+}
+
+/// Creates the backend, backends whose sample rate can change send the new rates through
+/// `sample_rates`.
+pub fn create(
+    options: Options,
+    jack: jack::Options,
+    status: Arc<Status>,
+    sample_rates: SyncSender<f64>,
+) -> Box<dyn AudioBackend> {
+    match options.backend {
+        Kind::Jack => Box::new(JackBackend::new(jack, status, sample_rates)),
+        Kind::Null => Box::new(NullBackend::new(&options, status)),
+        Kind::File => Box::new(FileBackend::new(options, status)),
+    }
+}
+
+/// Where a thread-driven backend's thread is at.
+#[derive(Default)]
+struct Worker {
+    stop: Arc<AtomicBool>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn spawn(&mut self, name: &str, f: impl FnOnce(&AtomicBool) + Send + 'static) {
+        self.running.store(true, Ordering::Release);
+        let stop = Arc::clone(&self.stop);
+        let running = Arc::clone(&self.running);
+        self.thread = Some(
+            thread::Builder::new()
+                .name(name.to_string())
+                .spawn(move || {
+                    f(&stop);
+                    running.store(false, Ordering::Release);
+                })
+                .expect("failed to spawn backend thread!"),
+        );
+    }
+
+    fn stop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            thread.join().expect("failed to join backend thread!");
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.running.load(Ordering::Acquire)
+    }
+}
+
+/// Renders in real time, paced by a timer, and discards the audio.
+/// Useful for running the engine without a sound server.
+pub struct NullBackend {
+    sample_rate: f64,
+    buffer_size: usize,
+    status: Arc<Status>,
+    worker: Worker,
+}
+
+impl NullBackend {
+    pub fn new(options: &Options, status: Arc<Status>) -> Self {
+        Self {
+            sample_rate: options.sample_rate as f64,
+            buffer_size: options.buffer_size.max(1),
+            status,
+            worker: Worker::default(),
+        }
+    }
+}
+
+impl AudioBackend for NullBackend {
+    fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    fn start(&mut self, engine: Arc<Mutex<Engine>>) {
+        let buffer_size = self.buffer_size;
+        let period = Duration::from_secs_f64(buffer_size as f64 / self.sample_rate);
+        let status = Arc::clone(&self.status);
+        status
+            .buffer_size
+            .store(buffer_size as u32, Ordering::Relaxed);
+        status.set_connection(Connection::Connected);
+
+        self.worker.spawn("rsynth-null", move |stop| {
+            let mut left = vec![0.; buffer_size];
+            let mut right = vec![0.; buffer_size];
+            let mut next = Instant::now();
+            while !stop.load(Ordering::Acquire) {
+                engine.lock().expect("failed to acquire lock!").process(
+                    std::iter::empty(),
+                    &mut left,
+                    Some(&mut right),
+                    [None, None],
+                );
+                next += period;
+                thread::sleep(next.saturating_duration_since(Instant::now()));
+            }
+            status.set_connection(Connection::Disconnected);
+        });
+    }
+
+    fn stop(&mut self) {
+        self.worker.stop();
+    }
+
+    fn is_running(&self) -> bool {
+        self.worker.is_running()
+    }
+
+    fn has_event_input(&self) -> bool {
+        false
+    }
+
+    fn output_channels(&self) -> usize {
+        2
+    }
+}
+
+/// Renders into a 32 bit float stereo WAV file as fast as possible.
+/// It stops after `length` seconds, or once the event source finished and the releases rang
+/// out, or when it's stopped. Without an event source it only renders the releases' length.
+/// Failing to write the file is logged and stops the backend.
+pub struct FileBackend {
+    path: PathBuf,
+    sample_rate: f64,
+    buffer_size: usize,
+    length: Option<f64>,
+    status: Arc<Status>,
+    worker: Worker,
+}
+
+impl FileBackend {
+    pub fn new(options: Options, status: Arc<Status>) -> Self {
+        Self {
+            path: options.output,
+            sample_rate: options.sample_rate as f64,
+            buffer_size: options.buffer_size.max(1),
+            length: options.length,
+            status,
+            worker: Worker::default(),
+        }
+    }
+}
+
+impl AudioBackend for FileBackend {
+    fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    fn start(&mut self, engine: Arc<Mutex<Engine>>) {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: self.sample_rate as u32,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = match hound::WavWriter::create(&self.path, spec) {
+            Ok(writer) => writer,
+            Err(err) => {
+                tracing::error!("failed to create {}: {err}", self.path.display());
+                return;
+            }
+        };
+        let path = self.path.clone();
+        let buffer_size = self.buffer_size;
+        let total = self
+            .length
+            .map(|length| (length * self.sample_rate) as usize);
+        let tail = (TAIL * self.sample_rate) as usize;
+        let status = Arc::clone(&self.status);
+        status
+            .buffer_size
+            .store(buffer_size as u32, Ordering::Relaxed);
+        status.set_connection(Connection::Connected);
+
+        self.worker.spawn("rsynth-file", move |stop| {
+            let mut left = vec![0.; buffer_size];
+            let mut right = vec![0.; buffer_size];
+            let mut rendered = 0;
+            let mut tail_left = None;
+            while !stop.load(Ordering::Acquire) {
+                let frames = match (total, tail_left) {
+                    (Some(total), _) => buffer_size.min(total - rendered),
+                    (None, Some(tail_left)) => buffer_size.min(tail_left),
+                    (None, None) => buffer_size,
+                };
+                if frames == 0 {
+                    break;
+                }
+
+                let mut engine = engine.lock().expect("failed to acquire lock!");
+                engine.process(
+                    std::iter::empty(),
+                    &mut left[..frames],
+                    Some(&mut right[..frames]),
+                    [None, None],
+                );
+                if tail_left.is_none() && engine.is_source_finished() {
+                    tail_left = Some(tail);
+                }
+                drop(engine);
+
+                let written =
+                    left[..frames]
+                        .iter()
+                        .zip(&right[..frames])
+                        .try_for_each(|(left, right)| {
+                            writer.write_sample(*left)?;
+                            writer.write_sample(*right)
+                        });
+                if let Err(err) = written {
+                    tracing::error!("failed to write {}: {err}", path.display());
+                    break;
+                }
+                rendered += frames;
+                tail_left = tail_left.map(|tail_left: usize| tail_left - frames.min(tail_left));
+            }
+            if let Err(err) = writer.finalize() {
+                tracing::error!("failed to finalize {}: {err}", path.display());
+            }
+            status.set_connection(Connection::Disconnected);
+        });
+    }
+
+    fn stop(&mut self) {
+        self.worker.stop();
+    }
+
+    fn is_running(&self) -> bool {
+        self.worker.is_running()
+    }
+    fn has_event_input(&self) -> bool {
+        false
+    }
+
+    fn output_channels(&self) -> usize {
+        2
+    }
+}
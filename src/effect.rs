@@ -0,0 +1,584 @@
+use crate::{
+    frame::{self, Frame},
+    shape::{self, Curve},
+};
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+
+/// Effect trait.
+/// Effects process the summed output of every key, in the order they're listed in the instrument.
+/// Anything an effect needs to remember between samples is skipped during (de)serialization
+/// and allocated in `prepare`.
+#[typetag::serde(tag = "type")]
+pub trait Effect: Debug + Send + Sync {
+    /// Allocates buffers for a specific sample rate, called before the first `process`.
+    fn prepare(&mut self, sample_rate: f64);
+    /// Processes a single frame.
+    /// `input` is the current frame of the audio input, for effects that use it as a modulator.
+    fn process(&mut self, frame: Frame, input: Frame) -> Frame;
+}
+
+/// Delay line with fractional read positions.
+#[derive(Debug, Default)]
+struct DelayLine {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl DelayLine {
+    fn new(length: usize) -> Self {
+        Self {
+            buffer: vec![0.; length.max(2)],
+            index: 0,
+        }
+    }
+
+    /// Returns the sample written `delay` samples ago, interpolating between neighbours.
+    fn read(&self, delay: f64) -> f32 {
+        let length = self.buffer.len();
+        if length == 0 {
+            return 0.;
+        }
+        let delay = delay.clamp(1., (length - 1) as f64);
+        let whole = delay.floor() as usize;
+        let fraction = (delay - whole as f64) as f32;
+        let a = self.buffer[(self.index + length - whole) % length];
+        let b = self.buffer[(self.index + length - whole - 1) % length];
+        a + (b - a) * fraction
+    }
+
+    fn write(&mut self, sample: f32) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        self.buffer[self.index] = sample;
+        self.index = (self.index + 1) % self.buffer.len();
+    }
+}
+
+/// Sine LFO.
+#[derive(Debug, Default)]
+struct Lfo {
+    phase: f64,
+    step: f64,
+}
+
+impl Lfo {
+    /// Creates an LFO starting at `phase`, in cycles.
+    fn new(rate: f64, sample_rate: f64, phase: f64) -> Self {
+        Self {
+            phase,
+            step: rate / sample_rate,
+        }
+    }
+
+    /// Returns the next value in `[-1, 1]`.
+    fn next(&mut self) -> f64 {
+        let value = (self.phase * 2. * ::std::f64::consts::PI).sin();
+        self.phase = (self.phase + self.step).fract();
+        value
+    }
+}
+
+/// Length of a delay.
+/// `Beats` syncs the delay to a tempo.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum DelayTime {
+    Seconds(f64),
+    Beats { bpm: f64, beats: f64 },
+}
+
+impl DelayTime {
+    fn seconds(&self) -> f64 {
+        match *self {
+            DelayTime::Seconds(seconds) => seconds,
+            DelayTime::Beats { bpm, beats } => 60. / bpm * beats,
+        }
+    }
+}
+
+/// Feedback delay.
+/// Repeats the signal after `time`, each repeat `feedback` times as loud as the previous one.
+/// `mix` is the ratio of the delayed signal to the dry one.
+/// With `ping_pong`, the repeats bounce between the left and the right channel.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Delay {
+    pub time: DelayTime,
+    pub feedback: f32,
+    pub mix: f32,
+    #[serde(default)]
+    pub ping_pong: bool,
+    #[serde(skip)]
+    lines: [DelayLine; 2],
+    #[serde(skip)]
+    delay: f64,
+}
+
+/// Chorus.
+/// Mixes in a copy of the signal whose delay is swept around `delay` seconds by `depth` seconds,
+/// `rate` times per second. The right channel is swept a quarter of a cycle behind the left one.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Chorus {
+    pub rate: f64,
+    pub delay: f64,
+    pub depth: f64,
+    #[serde(default)]
+    pub feedback: f32,
+    pub mix: f32,
+    #[serde(skip)]
+    lines: [DelayLine; 2],
+    #[serde(skip)]
+    lfos: [Lfo; 2],
+    #[serde(skip)]
+    sample_rate: f64,
+}
+
+/// Flanger.
+/// A chorus with a very short delay and feedback, producing a sweeping comb filter.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Flanger {
+    pub rate: f64,
+    pub depth: f64,
+    pub feedback: f32,
+    pub mix: f32,
+    #[serde(skip)]
+    lines: [DelayLine; 2],
+    #[serde(skip)]
+    lfo: Lfo,
+    #[serde(skip)]
+    sample_rate: f64,
+}
+
+/// Phaser.
+/// Sweeps the notches of `stages` allpass filters between `min_frequency` and `max_frequency`,
+/// `rate` times per second.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Phaser {
+    pub rate: f64,
+    pub stages: usize,
+    pub min_frequency: f64,
+    pub max_frequency: f64,
+    #[serde(default)]
+    pub feedback: f32,
+    pub mix: f32,
+    #[serde(skip)]
+    filters: [Vec<(f32, f32)>; 2],
+    #[serde(skip)]
+    lfo: Lfo,
+    #[serde(skip)]
+    last: Frame,
+    #[serde(skip)]
+    sample_rate: f64,
+}
+
+/// Algorithmic reverb (Freeverb).
+/// `room_size`, `damping` and `width` range from 0 to 1, `mix` is the ratio of the reverb to the dry signal.
+/// A `width` of 0 makes the reverb mono.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Reverb {
+    pub room_size: f32,
+    pub damping: f32,
+    pub mix: f32,
+    #[serde(default = "shape::one")]
+    pub width: f32,
+    #[serde(skip)]
+    combs: [Vec<Comb>; 2],
+    #[serde(skip)]
+    allpasses: [Vec<Allpass>; 2],
+}
+
+/// Waveshaper.
+/// Drives the signal into a transfer curve, `mix` is the ratio of the shaped signal to the dry one.
+/// With `oversampling` above 1 the signal is interpolated to that many points per sample before
+/// shaping and averaged afterwards, which keeps high drive from aliasing.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Waveshaper {
+    pub curve: Curve,
+    pub drive: f32,
+    #[serde(default = "shape::one")]
+    pub mix: f32,
+    #[serde(default = "shape::no_oversampling")]
+    pub oversampling: usize,
+    #[serde(skip)]
+    last: Frame,
+}
+
+/// Bitcrusher.
+/// Reduces the signal to `bits` of resolution, holding every value for `downsample` samples.
+/// `mix` is the ratio of the crushed signal to the dry one.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Bitcrusher {
+    pub bits: u32,
+    #[serde(default = "shape::no_downsampling")]
+    pub downsample: usize,
+    #[serde(default = "shape::one")]
+    pub mix: f32,
+    #[serde(skip)]
+    held: Frame,
+    #[serde(skip)]
+    counter: usize,
+}
+
+/// Channel vocoder.
+/// Splits the modulator into `bands` band-pass filters spaced evenly in pitch between
+/// `min_frequency` and `max_frequency`, follows the level of each band and imposes it
+/// on the same band of the carrier. The followers rise within about `attack` seconds
+/// and fall within about `release` seconds. `q` sets how narrow the bands are.
+/// With `modulator: Input` the audio input modulates the synth, with `modulator: Synth`
+/// the synth modulates the audio input.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Vocoder {
+    pub bands: usize,
+    pub min_frequency: f64,
+    pub max_frequency: f64,
+    pub attack: f64,
+    pub release: f64,
+    #[serde(default = "vocoder_q")]
+    pub q: f64,
+    #[serde(default)]
+    pub modulator: VocoderModulator,
+    #[serde(default = "shape::one")]
+    pub gain: f32,
+    #[serde(default = "shape::one")]
+    pub mix: f32,
+    #[serde(skip)]
+    filters: Vec<VocoderBand>,
+    #[serde(skip)]
+    attack_coefficient: f32,
+    #[serde(skip)]
+    release_coefficient: f32,
+}
+
+/// Which signal modulates the other in a `Vocoder`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub enum VocoderModulator {
+    #[default]
+    Input,
+    Synth,
+}
+
+#[derive(Debug)]
+struct VocoderBand {
+    modulator: Biquad,
+    carrier: [Biquad; 2],
+    envelope: f32,
+}
+
+fn vocoder_q() -> f64 {
+    5.
+}
+
+// Freeverb's tunings, in samples at 44.1 kHz
+const COMB_TUNINGS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNINGS: [usize; 4] = [556, 441, 341, 225];
+const REVERB_INPUT_GAIN: f32 = 0.015;
+const REVERB_WET_GAIN: f32 = 3.;
+// the right channel's delays are this many samples longer at 44.1 kHz
+const REVERB_STEREO_SPREAD: usize = 23;
+
+#[derive(Debug)]
+struct Comb {
+    buffer: Vec<f32>,
+    index: usize,
+    filter_store: f32,
+}
+
+#[derive(Debug)]
+struct Allpass {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl Comb {
+    fn process(&mut self, sample: f32, feedback: f32, damping: f32) -> f32 {
+        let out = self.buffer[self.index];
+        self.filter_store = out * (1. - damping) + self.filter_store * damping;
+        self.buffer[self.index] = sample + self.filter_store * feedback;
+        self.index = (self.index + 1) % self.buffer.len();
+        out
+    }
+}
+
+impl Allpass {
+    fn process(&mut self, sample: f32) -> f32 {
+        let buffered = self.buffer[self.index];
+        self.buffer[self.index] = sample + buffered * 0.5;
+        self.index = (self.index + 1) % self.buffer.len();
+        buffered - sample
+    }
+}
+
+/// Band-pass biquad filter with a peak gain of 0 dB.
+#[derive(Debug, Clone)]
+struct Biquad {
+    b0: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+impl Biquad {
+    fn band_pass(frequency: f64, q: f64, sample_rate: f64) -> Self {
+        let omega = 2. * ::std::f64::consts::PI * frequency / sample_rate;
+        let alpha = omega.sin() / (2. * q);
+        let a0 = 1. + alpha;
+        Self {
+            b0: (alpha / a0) as f32,
+            b2: (-alpha / a0) as f32,
+            a1: (-2. * omega.cos() / a0) as f32,
+            a2: ((1. - alpha) / a0) as f32,
+            x: [0., 0.],
+            y: [0., 0.],
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        // b1 is always 0 for a band-pass
+        let y = self.b0 * x + self.b2 * self.x[1] - self.a1 * self.y[0] - self.a2 * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+fn mix(dry: Frame, wet: Frame, mix: f32) -> Frame {
+    frame::add(frame::scale(dry, 1. - mix), frame::scale(wet, mix))
+}
+
+#[typetag::serde]
+impl Effect for Delay {
+    fn prepare(&mut self, sample_rate: f64) {
+        self.delay = self.time.seconds() * sample_rate;
+        self.lines = [(); 2].map(|_| DelayLine::new(self.delay.ceil() as usize + 1));
+    }
+
+    fn process(&mut self, frame: Frame, _input: Frame) -> Frame {
+        let delayed = [0, 1].map(|channel| self.lines[channel].read(self.delay));
+        if self.ping_pong {
+            self.lines[0].write(frame::mono(frame) + delayed[1] * self.feedback);
+            self.lines[1].write(delayed[0] * self.feedback);
+        } else {
+            for channel in 0..2 {
+                self.lines[channel].write(frame[channel] + delayed[channel] * self.feedback);
+            }
+        }
+        mix(frame, delayed, self.mix)
+    }
+}
+
+#[typetag::serde]
+impl Effect for Chorus {
+    fn prepare(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        let length = ((self.delay + self.depth) * sample_rate).ceil() as usize + 2;
+        self.lines = [(); 2].map(|_| DelayLine::new(length));
+        self.lfos = [0., 0.25].map(|phase| Lfo::new(self.rate, sample_rate, phase));
+    }
+
+    fn process(&mut self, frame: Frame, _input: Frame) -> Frame {
+        let delayed = [0, 1].map(|channel| {
+            let delay = (self.delay + self.depth * self.lfos[channel].next()) * self.sample_rate;
+            let delayed = self.lines[channel].read(delay);
+            self.lines[channel].write(frame[channel] + delayed * self.feedback);
+            delayed
+        });
+        mix(frame, delayed, self.mix)
+    }
+}
+
+#[typetag::serde]
+impl Effect for Flanger {
+    fn prepare(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        let length = (2. * self.depth * sample_rate).ceil() as usize + 2;
+        self.lines = [(); 2].map(|_| DelayLine::new(length));
+        self.lfo = Lfo::new(self.rate, sample_rate, 0.);
+    }
+
+    fn process(&mut self, frame: Frame, _input: Frame) -> Frame {
+        let delay = self.depth * (1. + self.lfo.next()) * self.sample_rate;
+        let delayed = [0, 1].map(|channel| {
+            let delayed = self.lines[channel].read(delay);
+            self.lines[channel].write(frame[channel] + delayed * self.feedback);
+            delayed
+        });
+        mix(frame, delayed, self.mix)
+    }
+}
+
+#[typetag::serde]
+impl Effect for Phaser {
+    fn prepare(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.filters = [(); 2].map(|_| vec![(0., 0.); self.stages]);
+        self.lfo = Lfo::new(self.rate, sample_rate, 0.);
+        self.last = [0., 0.];
+    }
+
+    fn process(&mut self, frame: Frame, _input: Frame) -> Frame {
+        let sweep = (self.lfo.next() + 1.) / 2.;
+        let frequency = self.min_frequency * (self.max_frequency / self.min_frequency).powf(sweep);
+        let tan = (::std::f64::consts::PI * frequency / self.sample_rate).tan();
+        let coefficient = ((tan - 1.) / (tan + 1.)) as f32;
+
+        let wet = [0, 1].map(|channel| {
+            self.filters[channel].iter_mut().fold(
+                frame[channel] + self.last[channel] * self.feedback,
+                |x, (x1, y1)| {
+                    let y = coefficient * x + *x1 - coefficient * *y1;
+                    *x1 = x;
+                    *y1 = y;
+                    y
+                },
+            )
+        });
+        self.last = wet;
+        mix(frame, wet, self.mix)
+    }
+}
+
+#[typetag::serde]
+impl Effect for Reverb {
+    fn prepare(&mut self, sample_rate: f64) {
+        let scale = sample_rate / 44100.;
+        let length = |tuning: usize, channel: usize| {
+            (((tuning + channel * REVERB_STEREO_SPREAD) as f64 * scale) as usize).max(1)
+        };
+        self.combs = [0, 1].map(|channel| {
+            COMB_TUNINGS
+                .iter()
+                .map(|tuning| Comb {
+                    buffer: vec![0.; length(*tuning, channel)],
+                    index: 0,
+                    filter_store: 0.,
+                })
+                .collect()
+        });
+        self.allpasses = [0, 1].map(|channel| {
+            ALLPASS_TUNINGS
+                .iter()
+                .map(|tuning| Allpass {
+                    buffer: vec![0.; length(*tuning, channel)],
+                    index: 0,
+                })
+                .collect()
+        });
+    }
+
+    fn process(&mut self, frame: Frame, _input: Frame) -> Frame {
+        let feedback = self.room_size * 0.28 + 0.7;
+        let damping = self.damping * 0.4;
+        let input = frame::mono(frame) * REVERB_INPUT_GAIN;
+
+        let wet = [0, 1].map(|channel| {
+            let wet = self.combs[channel]
+                .iter_mut()
+                .map(|comb| comb.process(input, feedback, damping))
+                .sum::<f32>();
+            self.allpasses[channel]
+                .iter_mut()
+                .fold(wet, |x, allpass| allpass.process(x))
+        });
+        let direct = REVERB_WET_GAIN * (1. + self.width) / 2.;
+        let crossed = REVERB_WET_GAIN * (1. - self.width) / 2.;
+        let wet = [
+            wet[0] * direct + wet[1] * crossed,
+            wet[1] * direct + wet[0] * crossed,
+        ];
+        mix(frame, wet, self.mix)
+    }
+}
+
+#[typetag::serde]
+impl Effect for Waveshaper {
+    fn prepare(&mut self, _sample_rate: f64) {
+        self.last = [0., 0.];
+    }
+
+    fn process(&mut self, frame: Frame, _input: Frame) -> Frame {
+        let oversampling = self.oversampling.max(1);
+        let shaped = [0, 1].map(|channel| {
+            let last = self.last[channel];
+            (1..=oversampling)
+                .map(|i| {
+                    let x = last + (frame[channel] - last) * i as f32 / oversampling as f32;
+                    shape::shape(&self.curve, self.drive, self.mix, x)
+                })
+                .sum::<f32>()
+                / oversampling as f32
+        });
+        self.last = frame;
+        shaped
+    }
+}
+
+#[typetag::serde]
+impl Effect for Bitcrusher {
+    fn prepare(&mut self, _sample_rate: f64) {
+        self.held = [0., 0.];
+        self.counter = 0;
+    }
+
+    fn process(&mut self, frame: Frame, _input: Frame) -> Frame {
+        if self.counter == 0 {
+            self.held = frame.map(|sample| shape::crush(sample, self.bits));
+        }
+        self.counter = (self.counter + 1) % self.downsample.max(1);
+        mix(frame, self.held, self.mix)
+    }
+}
+
+#[typetag::serde]
+impl Effect for Vocoder {
+    fn prepare(&mut self, sample_rate: f64) {
+        let nyquist = sample_rate / 2.;
+        let max_frequency = self.max_frequency.min(nyquist * 0.9);
+        let ratio = max_frequency / self.min_frequency;
+        self.filters = (0..self.bands)
+            .map(|i| {
+                let position = if self.bands > 1 {
+                    i as f64 / (self.bands - 1) as f64
+                } else {
+                    0.5
+                };
+                let filter = Biquad::band_pass(
+                    self.min_frequency * ratio.powf(position),
+                    self.q,
+                    sample_rate,
+                );
+                VocoderBand {
+                    modulator: filter.clone(),
+                    carrier: [filter.clone(), filter],
+                    envelope: 0.,
+                }
+            })
+            .collect();
+        let coefficient = |time: f64| (1. - (-1. / (time * sample_rate)).exp()) as f32;
+        self.attack_coefficient = coefficient(self.attack);
+        self.release_coefficient = coefficient(self.release);
+    }
+
+    fn process(&mut self, frame: Frame, input: Frame) -> Frame {
+        let (carrier, modulator) = match self.modulator {
+            VocoderModulator::Input => (frame, frame::mono(input)),
+            VocoderModulator::Synth => (input, frame::mono(frame)),
+        };
+
+        let mut wet = [0., 0.];
+        for band in &mut self.filters {
+            let level = band.modulator.process(modulator).abs();
+            let coefficient = if level > band.envelope {
+                self.attack_coefficient
+            } else {
+                self.release_coefficient
+            };
+            band.envelope += (level - band.envelope) * coefficient;
+            for channel in 0..2 {
+                wet[channel] += band.carrier[channel].process(carrier[channel]) * band.envelope;
+            }
+        }
+        mix(frame, frame::scale(wet, self.gain), self.mix)
+    }
+}
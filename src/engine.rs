@@ -0,0 +1,624 @@
+use crate::{
+    event::{EventSource, Events},
+    frame::{self, Frame},
+    instrument::Instrument,
+    key::Key,
+    master::Master,
+    midi::{ChannelMessageKind, Message, Midi, SystemMessageKind},
+    pool::{Job, Pool},
+    record::{AudioCapture, MidiCapture},
+    session,
+    state::State,
+};
+use std::{
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering},
+        mpsc::{Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Most frames rendered at once, longer periods are split into blocks of this size.
+pub const BLOCK_SIZE: usize = 256;
+
+/// Least time between two reports of the same problem.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Highest sample rate the master section reserves memory for, so switching to any rate up to
+/// it on the audio thread doesn't allocate.
+const MAX_SAMPLE_RATE: f64 = 384000.;
+
+/// State of the connection to the audio server.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Connection {
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+/// Status of the engine and the audio server, shared with the UI.
+/// Everything in here is atomic, so the audio thread never has to wait for anyone to update it.
+/// The audio thread never logs either, it counts problems here and `Reporter` logs them.
+#[derive(Default)]
+pub struct Status {
+    pub should_redraw: AtomicBool,
+    peak: AtomicU32,
+    pub clipped_samples: AtomicU64,
+    /// MIDI messages that failed to parse and were skipped.
+    pub midi_errors: AtomicU64,
+    connection: AtomicU8,
+    pub sample_rate: AtomicU32,
+    pub buffer_size: AtomicU32,
+    pub xruns: AtomicU64,
+    /// Whether the MIDI the engine plays is being recorded.
+    pub record_midi: AtomicBool,
+    /// Whether the audio output is being recorded.
+    pub record_audio: AtomicBool,
+}
+
+impl Status {
+    pub fn peak(&self) -> f32 {
+        f32::from_bits(self.peak.load(Ordering::Relaxed))
+    }
+
+    pub fn connection(&self) -> Connection {
+        match self.connection.load(Ordering::Relaxed) {
+            1 => Connection::Connecting,
+            2 => Connection::Connected,
+            _ => Connection::Disconnected,
+        }
+    }
+
+    pub fn set_connection(&self, connection: Connection) {
+        self.connection.store(connection as u8, Ordering::Relaxed);
+        self.should_redraw.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Logs the problems the audio thread counts in `Status`, at most once a second each.
+/// Meant to be polled outside of the audio thread.
+#[derive(Default)]
+pub struct Reporter {
+    clipped_samples: u64,
+    last_clip_report: Option<Instant>,
+    midi_errors: u64,
+    last_midi_report: Option<Instant>,
+}
+
+impl Reporter {
+    pub fn report(&mut self, status: &Status) {
+        let now = Instant::now();
+        let due = |last: Option<Instant>| last.is_none_or(|last| now - last >= REPORT_INTERVAL);
+        let clipped_samples = status.clipped_samples.load(Ordering::Relaxed);
+        if clipped_samples > self.clipped_samples && due(self.last_clip_report) {
+            let clipped = clipped_samples - self.clipped_samples;
+            tracing::warn!("output clipped {clipped} samples! peak: {}", status.peak());
+            self.clipped_samples = clipped_samples;
+            self.last_clip_report = Some(now);
+        }
+        let midi_errors = status.midi_errors.load(Ordering::Relaxed);
+        if midi_errors > self.midi_errors && due(self.last_midi_report) {
+            let skipped = midi_errors - self.midi_errors;
+            tracing::warn!("skipped {skipped} midi events that failed to parse");
+            self.midi_errors = midi_errors;
+            self.last_midi_report = Some(now);
+        }
+    }
+}
+
+/// An instrument together with the per-key state of its oscillators.
+/// Patches are built and prepared outside of the audio thread, so all of their allocations
+/// happen before the engine ever sees them.
+/// A patch only fits the sample rate it was prepared for, the engine drops patches of another.
+pub struct Patch {
+    instrument: Instrument,
+    states: Vec<State>,
+    sample_rate: f64,
+}
+
+impl Patch {
+    pub fn new(mut instrument: Instrument, sample_rate: f64) -> Box<Self> {
+        instrument.prepare(sample_rate);
+        // every velocity at which the zones a key falls into can change
+        let mut velocities = vec![1];
+        for zone in &instrument.zones {
+            velocities.extend([zone.velocities.0, zone.velocities.1.saturating_add(1)]);
+        }
+        velocities.retain(|velocity| (1..=127).contains(velocity));
+        velocities.sort();
+        velocities.dedup();
+        // play every key once at each of them, so stateful oscillators create their slots now
+        let mut states = (0..256)
+            .map(|_| State::new(sample_rate))
+            .collect::<Vec<_>>();
+        for velocity in velocities {
+            let key = Key {
+                active: true,
+                time_pressed: 0.,
+                time_released: 0.,
+                velocity,
+            };
+            for (key_number, state) in states.iter_mut().enumerate() {
+                instrument.render_voice(key_number, &key, state, 0., &[[0., 0.]], &mut [[0., 0.]]);
+            }
+        }
+        Box::new(Self {
+            instrument,
+            states,
+            sample_rate,
+        })
+    }
+}
+
+/// Sends a part's patch to the engine without ever making the audio thread wait.
+/// Returned patches are dropped here first, so they're freed on the calling thread.
+pub fn send_patch(
+    part: usize,
+    patch: Box<Patch>,
+    patches: &SyncSender<(usize, Box<Patch>)>,
+    garbage: &Receiver<Box<Patch>>,
+) {
+    let mut patch = (part, patch);
+    loop {
+        while garbage.try_recv().is_ok() {}
+        match patches.try_send(patch) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => return,
+            Err(TrySendError::Full(returned)) => {
+                patch = returned;
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        }
+    }
+}
+
+/// An instrument of the session with the keys played on it.
+pub struct Part {
+    patch: Box<Patch>,
+    /// Channel the part listens to, counted from 0, or every channel.
+    channel: Option<u8>,
+    volume: f32,
+    pan: f32,
+    keys: [Key; 256],
+}
+
+impl Part {
+    pub fn new(part: &session::Part, patch: Box<Patch>) -> Self {
+        Self {
+            patch,
+            channel: part.channel.map(|channel| channel - 1),
+            volume: part.volume,
+            pan: part.pan,
+            // never released, so a key pressed right at the start counts as pressed
+            keys: [Key {
+                active: false,
+                time_pressed: 0.,
+                time_released: f64::NEG_INFINITY,
+                velocity: 0,
+            }; 256],
+        }
+    }
+
+    fn listens_to(&self, channel: u8) -> bool {
+        self.channel.is_none() || self.channel == Some(channel)
+    }
+
+    fn release_all(&mut self, time: f64) {
+        for (key, state) in self.keys.iter_mut().zip(&mut self.patch.states) {
+            if key.is_pressed() {
+                key.time_released = time;
+                state.released = true;
+            }
+        }
+    }
+}
+
+/// The synthesizer itself, owned by the audio thread.
+/// New patches arrive through `patches`, together with the part they're for, and replaced ones
+/// leave through `garbage`, both are only ever polled, so the engine never blocks and never
+/// frees memory. New sample rates arrive the same way through `sample_rates`.
+pub struct Engine {
+    parts: Vec<Part>,
+    /// Applied to the sum of all parts.
+    master: Option<Master>,
+    patches: Receiver<(usize, Box<Patch>)>,
+    garbage: SyncSender<Box<Patch>>,
+    pending_garbage: Option<Box<Patch>>,
+    sample_rates: Option<Receiver<f64>>,
+    status: Arc<Status>,
+    /// Numbers of the keys sounding in the current block.
+    active: Vec<usize>,
+    inputs: [Frame; BLOCK_SIZE],
+    block: [Frame; BLOCK_SIZE],
+    /// Sum of the parts' blocks.
+    mix: [Frame; BLOCK_SIZE],
+    pool: Pool,
+    time: f64,
+    sample_rate: f64,
+    frame_t: f64,
+    source: Option<Box<dyn EventSource>>,
+    events: Events,
+    midi_capture: Option<MidiCapture>,
+    audio_capture: Option<AudioCapture>,
+}
+
+impl Engine {
+    pub fn new(
+        parts: Vec<Part>,
+        sample_rate: f64,
+        patches: Receiver<(usize, Box<Patch>)>,
+        garbage: SyncSender<Box<Patch>>,
+        status: Arc<Status>,
+        threads: usize,
+        source: Option<Box<dyn EventSource>>,
+    ) -> Self {
+        Self {
+            parts,
+            master: None,
+            patches,
+            garbage,
+            pending_garbage: None,
+            sample_rates: None,
+            status,
+            active: Vec::with_capacity(256),
+            inputs: [[0., 0.]; BLOCK_SIZE],
+            block: [[0., 0.]; BLOCK_SIZE],
+            mix: [[0., 0.]; BLOCK_SIZE],
+            pool: Pool::new(threads),
+            time: 0.,
+            sample_rate,
+            frame_t: 1. / sample_rate,
+            source,
+            events: Events::default(),
+            midi_capture: None,
+            audio_capture: None,
+        }
+    }
+
+    /// Runs the sum of all parts through `master`.
+    /// This allocates, so it must not be called from the audio thread.
+    pub fn set_master(&mut self, mut master: Master) {
+        master.prepare(MAX_SAMPLE_RATE);
+        master.prepare(self.sample_rate);
+        self.master = Some(master);
+    }
+
+    /// Passes the MIDI the engine plays on to a recorder, see `Status::record_midi`.
+    pub fn set_midi_capture(&mut self, capture: MidiCapture) {
+        self.midi_capture = Some(capture);
+    }
+
+    /// Passes the output on to a recorder, see `Status::record_audio`.
+    pub fn set_audio_capture(&mut self, capture: AudioCapture) {
+        self.audio_capture = Some(capture);
+    }
+
+    /// Whether the event source has no more events, which is always the case without a source.
+    pub fn is_source_finished(&self) -> bool {
+        self.source
+            .as_ref()
+            .is_none_or(|source| source.is_finished())
+    }
+
+    /// Switches to the sample rates sent through `sample_rates` while the engine is running,
+    /// see `Engine::receive_sample_rate`.
+    pub fn set_sample_rates(&mut self, sample_rates: Receiver<f64>) {
+        self.sample_rates = Some(sample_rates);
+    }
+
+    /// Prepares the current patches for a new sample rate, if it changed.
+    /// This allocates, so it must only be called while no backend is running the engine.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        // rates sent while an earlier client was running are out of date
+        if let Some(sample_rates) = &self.sample_rates {
+            while sample_rates.try_recv().is_ok() {}
+        }
+        if sample_rate == self.sample_rate {
+            return;
+        }
+        for part in &mut self.parts {
+            part.patch.instrument.prepare(sample_rate);
+            part.patch.sample_rate = sample_rate;
+        }
+        self.switch_sample_rate(sample_rate);
+    }
+
+    /// Switches to the newest sample rate that arrived, if any.
+    /// The patches' instruments stay prepared for the old rate until their rebuilt patches
+    /// arrive, since preparing them allocates.
+    fn receive_sample_rate(&mut self) {
+        let Some(sample_rates) = &self.sample_rates else {
+            return;
+        };
+        if let Some(sample_rate) = sample_rates.try_iter().last() {
+            if sample_rate != self.sample_rate {
+                self.switch_sample_rate(sample_rate);
+            }
+        }
+    }
+
+    /// Moves time, the master section and the keys' states to a new sample rate, without
+    /// allocating as long as it's at most `MAX_SAMPLE_RATE`.
+    fn switch_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.frame_t = 1. / sample_rate;
+        if let Some(master) = &mut self.master {
+            master.prepare(sample_rate);
+        }
+        for part in &mut self.parts {
+            for state in &mut part.patch.states {
+                let released = state.released;
+                state.sample_rate = sample_rate;
+                state.reset(state.time_pressed);
+                state.released = released;
+            }
+        }
+    }
+
+    /// Hands a replaced patch back, keeping it around if there's no room for it yet.
+    fn discard(&mut self, patch: Box<Patch>) -> bool {
+        match self.garbage.try_send(patch) {
+            Ok(()) => true,
+            Err(TrySendError::Full(patch) | TrySendError::Disconnected(patch)) => {
+                self.pending_garbage = Some(patch);
+                false
+            }
+        }
+    }
+
+    /// Switches parts to their newest patch, if any arrived.
+    /// Patches for another sample rate are dropped, the ones for the current rate follow them.
+    fn receive_patches(&mut self) {
+        if let Some(patch) = self.pending_garbage.take() {
+            if !self.discard(patch) {
+                return;
+            }
+        }
+        while let Ok((index, mut patch)) = self.patches.try_recv() {
+            let part = match self.parts.get_mut(index) {
+                Some(part) if patch.sample_rate == self.sample_rate => part,
+                _ => {
+                    if !self.discard(patch) {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            // restart sounding keys with the new patch, keeping their timing
+            for ((new, old), key) in patch
+                .states
+                .iter_mut()
+                .zip(&part.patch.states)
+                .zip(&part.keys)
+                .filter(|(_, key)| key.active)
+            {
+                new.reset(key.time_pressed);
+                new.released = old.released;
+            }
+            let old = std::mem::replace(&mut part.patch, patch);
+            if !self.discard(old) {
+                return;
+            }
+        }
+    }
+
+    fn handle(&mut self, midi: Midi) {
+        let time = self.time;
+        match midi.message {
+            Message::ChannelMessage { channel, kind } => {
+                let parts = self
+                    .parts
+                    .iter_mut()
+                    .filter(|part| part.listens_to(channel));
+                match kind {
+                    // a note on without velocity is a note off, files and running status use it
+                    // a lot
+                    ChannelMessageKind::NoteOff { key_number, .. }
+                    | ChannelMessageKind::NoteOn {
+                        key_number,
+                        velocity: 0,
+                    } => {
+                        for part in parts {
+                            part.keys[key_number as usize].time_released = time;
+                            part.patch.states[key_number as usize].released = true;
+                        }
+                    }
+                    ChannelMessageKind::NoteOn {
+                        key_number,
+                        velocity,
+                    } => {
+                        for part in parts {
+                            let key = &mut part.keys[key_number as usize];
+                            key.active = true;
+                            key.time_pressed = time;
+                            key.velocity = velocity;
+                            part.patch.states[key_number as usize].reset(time);
+                        }
+                    }
+                    // controllers, program changes and pitch bends aren't played yet, and warning
+                    // about them from the audio thread would flood the log
+                    _ => (),
+                }
+            }
+            // clock, transport and system exclusive messages don't affect the sound
+            Message::SystemMessage { kind } => {
+                if let SystemMessageKind::SystemReset = kind {
+                    self.parts
+                        .iter_mut()
+                        .for_each(|part| part.release_all(time));
+                }
+            }
+        }
+    }
+
+    /// Renders the voices of a part's sounding keys for the first `len` frames of `inputs`
+    /// into `block`.
+    fn render(&mut self, part: usize, len: usize) {
+        let part = &mut self.parts[part];
+        let Patch {
+            instrument, states, ..
+        } = &mut *part.patch;
+        self.active.clear();
+        self.active.extend(
+            part.keys
+                .iter()
+                .enumerate()
+                .filter(|(_i, key)| key.active)
+                .map(|(i, _key)| i),
+        );
+        let block = &mut self.block[..len];
+        block.fill([0., 0.]);
+        let job = Job::new(
+            instrument,
+            &part.keys,
+            states,
+            &self.active,
+            self.time,
+            &self.inputs[..len],
+        );
+        self.pool.render(job, block);
+        instrument.mix(block);
+    }
+
+    /// Renders the frames in `range` of the period, a block at a time.
+    fn render_range(
+        &mut self,
+        range: Range<usize>,
+        left: &mut [f32],
+        right: &mut Option<&mut [f32]>,
+        inputs: [Option<&[f32]>; 2],
+    ) {
+        let time = self.time;
+        for part in &mut self.parts {
+            let instrument = &part.patch.instrument;
+            part.keys
+                .iter_mut()
+                .enumerate()
+                .filter(|(i, key)| {
+                    key.active && !key.is_pressed() && instrument.is_silent(*i, key, time)
+                })
+                .for_each(|(_i, key)| key.active = false);
+        }
+
+        for start in range.clone().step_by(BLOCK_SIZE) {
+            let len = (range.end - start).min(BLOCK_SIZE);
+            for (i, input) in self.inputs[..len].iter_mut().enumerate() {
+                let iv = start + i;
+                *input = match inputs {
+                    [Some(left), Some(right)] => [left[iv], right[iv]],
+                    [Some(mono), None] => [mono[iv], mono[iv]],
+                    _ => [0., 0.],
+                };
+            }
+            self.mix[..len].fill([0., 0.]);
+            let mut all_voices = 0;
+            for index in 0..self.parts.len() {
+                self.render(index, len);
+                let part = &mut self.parts[index];
+                let voices = part.keys.iter().filter(|key| key.active).count();
+                all_voices += voices;
+                for i in 0..len {
+                    let frame =
+                        part.patch
+                            .instrument
+                            .process(self.block[i], self.inputs[i], voices);
+                    let frame = frame::balance(frame::scale(frame, part.volume), part.pan);
+                    self.mix[i] = frame::add(self.mix[i], frame);
+                }
+            }
+            for i in 0..len {
+                let iv = start + i;
+                let frame = match &mut self.master {
+                    Some(master) => master.process(self.mix[i], all_voices),
+                    None => self.mix[i],
+                };
+                match right {
+                    Some(right) => {
+                        left[iv] = frame[0];
+                        right[iv] = frame[1];
+                    }
+                    None => left[iv] = frame::mono(frame),
+                }
+            }
+            self.time += self.frame_t * len as f64;
+        }
+    }
+
+    /// Renders one period into `left` and `right`, or mixed down into `left` if there's no `right`.
+    /// `inputs` are the audio input's channels, a single one being mono.
+    /// `events` are raw MIDI messages with the frame of the period they happen at, in order,
+    /// they're merged with the events of the engine's event source.
+    /// Rendering stops at every event, so notes start and stop exactly on their frame.
+    /// Messages that fail to parse are counted and skipped.
+    pub fn process<'a>(
+        &mut self,
+        events: impl IntoIterator<Item = (usize, &'a [u8])>,
+        left: &mut [f32],
+        mut right: Option<&mut [f32]>,
+        inputs: [Option<&[f32]>; 2],
+    ) {
+        self.receive_sample_rate();
+        self.receive_patches();
+
+        // taken out for the loop, which needs the engine, and put back afterwards,
+        // the placeholder left behind doesn't allocate
+        let mut merged = std::mem::replace(&mut self.events, Events::empty());
+        merged.clear();
+        events
+            .into_iter()
+            .for_each(|(frame, bytes)| merged.push(frame, bytes));
+        if let Some(source) = &mut self.source {
+            source.read(left.len(), self.sample_rate, &mut merged);
+        }
+        merged.sort();
+        if let Some(capture) = &mut self.midi_capture {
+            capture.update(&self.status, self.time);
+        }
+
+        let mut position = 0;
+        for (frame, bytes) in merged.iter() {
+            // out of order or late events are played as soon as possible
+            let frame = frame.clamp(position, left.len());
+            self.render_range(position..frame, left, &mut right, inputs);
+            match Midi::try_from(bytes) {
+                Ok(midi) => {
+                    if let Some(capture) = &mut self.midi_capture {
+                        capture.message(self.time, bytes);
+                    }
+                    self.handle(midi);
+                }
+                Err(_) => {
+                    self.status.midi_errors.fetch_add(1, Ordering::Relaxed);
+                    self.status.should_redraw.store(true, Ordering::Relaxed);
+                }
+            }
+            position = frame;
+        }
+        self.render_range(position..left.len(), left, &mut right, inputs);
+        self.events = merged;
+        if let Some(capture) = &mut self.audio_capture {
+            capture.process(&self.status, self.sample_rate, left, right.as_deref());
+        }
+
+        let samples = left
+            .iter()
+            .chain(right.iter().flat_map(|right| right.iter()));
+        let peak = samples.clone().fold(0f32, |peak, v| peak.max(v.abs()));
+        let clipped = samples.filter(|v| v.abs() > 1.).count() as u64;
+        self.status.peak.store(peak.to_bits(), Ordering::Relaxed);
+        if clipped > 0 {
+            self.status
+                .clipped_samples
+                .fetch_add(clipped, Ordering::Relaxed);
+            self.status.should_redraw.store(true, Ordering::Relaxed);
+        }
+
+        if self.time >= f64::MAX {
+            self.time = 0.;
+            self.parts
+                .iter_mut()
+                .for_each(|part| part.keys.iter_mut().for_each(|x| x.active = false));
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use crate::key::Key;
 use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Copy, Deserialize, Serialize)]
 pub enum Envelope {
     ADSR {
         attack_time: f64,
@@ -16,6 +16,30 @@ pub enum Envelope {
 }
 
 impl Envelope {
+    /// Returns a copy of the envelope with every stage's duration multiplied by `factor`.
+    pub fn scale_time(&self, factor: f64) -> Envelope {
+        match *self {
+            Envelope::ADSR {
+                attack_time,
+                decay_time,
+                sustain_amplitude,
+                release_time,
+            } => Envelope::ADSR {
+                attack_time: attack_time * factor,
+                decay_time: decay_time * factor,
+                sustain_amplitude,
+                release_time: release_time * factor,
+            },
+            Envelope::AD {
+                attack_time,
+                decay_time,
+            } => Envelope::AD {
+                attack_time: attack_time * factor,
+                decay_time: decay_time * factor,
+            },
+        }
+    }
+
     pub fn amplitude(&self, key: &Key, time: f64) -> f64 {
         match *self {
             Envelope::ADSR {
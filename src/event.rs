@@ -0,0 +1,84 @@
+/// Bytes of MIDI the buffers of `Events` have room for before they have to grow.
+const EVENT_BYTES: usize = 4096;
+const EVENT_COUNT: usize = 1024;
+
+/// Raw MIDI events of one period, each with the frame it happens at.
+/// The buffers are allocated up front and only cleared between periods, so filling them on the
+/// audio thread doesn't allocate unless a period holds an unusual amount of MIDI.
+pub struct Events {
+    bytes: Vec<u8>,
+    /// Frame, start and end in `bytes` of every event.
+    events: Vec<(usize, usize, usize)>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            bytes: Vec::with_capacity(EVENT_BYTES),
+            events: Vec::with_capacity(EVENT_COUNT),
+        }
+    }
+}
+
+impl Events {
+    /// Events without any buffers, a placeholder that doesn't allocate.
+    pub fn empty() -> Self {
+        Self {
+            bytes: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+        self.events.clear();
+    }
+
+    pub fn push(&mut self, frame: usize, bytes: &[u8]) {
+        let start = self.bytes.len();
+        self.bytes.extend_from_slice(bytes);
+        self.events.push((frame, start, self.bytes.len()));
+    }
+
+    /// Orders the events by frame, keeping the order of events on the same frame.
+    pub fn sort(&mut self) {
+        // insertion sort, it's stable and doesn't allocate, and events mostly arrive in order
+        for i in 1..self.events.len() {
+            let mut j = i;
+            while j > 0 && self.events[j - 1].0 > self.events[j].0 {
+                self.events.swap(j - 1, j);
+                j -= 1;
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        self.events
+            .iter()
+            .map(|&(frame, start, end)| (frame, &self.bytes[start..end]))
+    }
+}
+
+/// A source of MIDI events that isn't part of the audio backend, like a MIDI device or a file.
+/// The engine reads it every period, merging its events with the backend's.
+pub trait EventSource: Send {
+    /// Adds the events of the next `frames` frames to `events`, at the frame they happen at.
+    fn read(&mut self, frames: usize, sample_rate: f64, events: &mut Events);
+
+    /// Whether the source won't produce any more events, which ends offline rendering.
+    fn is_finished(&self) -> bool {
+        false
+    }
+}
+
+/// Several sources read one after the other, finished once all of them are.
+impl EventSource for Vec<Box<dyn EventSource>> {
+    fn read(&mut self, frames: usize, sample_rate: f64, events: &mut Events) {
+        self.iter_mut()
+            .for_each(|source| source.read(frames, sample_rate, events));
+    }
+
+    fn is_finished(&self) -> bool {
+        self.iter().all(|source| source.is_finished())
+    }
+}
@@ -0,0 +1,23 @@
+/// A stereo sample, left channel first.
+pub type Frame = [f32; 2];
+
+/// Moves a stereo sample towards one side by turning the other side down, `pan` going from
+/// -1 (left) to 1 (right). A centered sample comes out at full level on both channels,
+/// the same as a mono signal.
+pub fn balance(frame: Frame, pan: f32) -> Frame {
+    let pan = pan.clamp(-1., 1.);
+    [frame[0] * (1. - pan).min(1.), frame[1] * (1. + pan).min(1.)]
+}
+
+/// Mixes a stereo sample down to mono.
+pub fn mono(frame: Frame) -> f32 {
+    (frame[0] + frame[1]) / 2.
+}
+
+pub fn add(a: Frame, b: Frame) -> Frame {
+    [a[0] + b[0], a[1] + b[1]]
+}
+
+pub fn scale(frame: Frame, factor: f32) -> Frame {
+    [frame[0] * factor, frame[1] * factor]
+}
@@ -1,4 +1,14 @@
-use crate::{envelope::Envelope, hz::Hz, key::Key, osc::Oscillator};
+use crate::{
+    effect::Effect,
+    envelope::Envelope,
+    frame::{self, Frame},
+    hz::{Hertz, Hz},
+    key::Key,
+    master::Master,
+    osc::Oscillator,
+    state::State,
+    zone::Zone,
+};
 use serde::{Deserialize, Serialize};
 use std::{fs, io, path::Path};
 
@@ -10,8 +20,58 @@ const A4_FREQUENCY: f64 = 440.;
 #[derive(Deserialize, Serialize)]
 pub struct Instrument {
     pub volume: f32,
+    #[serde(default)]
+    pub pan: f32,
     pub envelope: Envelope,
     pub oscillator: Box<dyn Oscillator>,
+    #[serde(default)]
+    pub key_follow: KeyFollow,
+    #[serde(default)]
+    pub input_level: f32,
+    #[serde(default)]
+    pub effects: Vec<Box<dyn Effect>>,
+    #[serde(default)]
+    pub master: Master,
+    /// Sub-instruments for ranges of keys and velocities.
+    /// Keys and velocities outside of every zone play the instrument's own oscillator.
+    #[serde(default)]
+    pub zones: Vec<Zone>,
+}
+
+/// Keyboard tracking.
+/// Scales envelope times and the oscillator level by the distance from `center_key`.
+/// `time` and `amplitude` are factors applied once per octave above the center key,
+/// so `time: 0.5` halves every envelope stage one octave up and doubles it one octave down.
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct KeyFollow {
+    pub center_key: u8,
+    pub time: f64,
+    pub amplitude: f64,
+}
+
+impl Default for KeyFollow {
+    fn default() -> Self {
+        Self {
+            center_key: 60,
+            time: 1.,
+            amplitude: 1.,
+        }
+    }
+}
+
+impl KeyFollow {
+    fn octaves(&self, key_number: usize) -> f64 {
+        (key_number as f64 - self.center_key as f64) / 12.
+    }
+
+    pub fn time_factor(&self, key_number: usize) -> f64 {
+        self.time.powf(self.octaves(key_number))
+    }
+
+    pub fn amplitude_factor(&self, key_number: usize) -> f64 {
+        self.amplitude.powf(self.octaves(key_number))
+    }
 }
 
 #[derive(Debug)]
@@ -28,17 +88,101 @@ impl Instrument {
         }
     }
 
-    pub fn value(&self, keys: &[Key; 256], time: f64) -> f32 {
-        keys.into_iter()
-            .enumerate()
-            .filter(|(_i, key)| key.active)
-            .map(|(i, key)| {
-                self.envelope.amplitude(key, time) as f32
-                    * self
-                        .oscillator
-                        .value((A4_FREQUENCY * STEP_BASE.powi(i as i32 - 57)).hz(), time)
-            })
-            .sum::<f32>()
-            * self.volume
+    /// Prepares the effects and the master section for a specific sample rate.
+    pub fn prepare(&mut self, sample_rate: f64) {
+        self.effects
+            .iter_mut()
+            .for_each(|effect| effect.prepare(sample_rate));
+        self.master.prepare(sample_rate);
+    }
+
+    /// Mixes in the audio input at `input_level` and runs the result through the effects and
+    /// the master section, `voices` being the number of keys currently sounding.
+    pub fn process(&mut self, frame: Frame, input: Frame, voices: usize) -> Frame {
+        let frame = frame::add(frame, frame::scale(input, self.input_level));
+        let frame = self
+            .effects
+            .iter_mut()
+            .fold(frame, |frame, effect| effect.process(frame, input));
+        self.master.process(frame, voices)
+    }
+
+    /// Returns the frequency of a specific key.
+    pub fn frequency(key_number: usize) -> Hertz<f64> {
+        (A4_FREQUENCY * STEP_BASE.powi(key_number as i32 - 57)).hz()
+    }
+
+    /// Whether a released key's voice has faded out, in every zone it plays.
+    pub fn is_silent(&self, key_number: usize, key: &Key, time: f64) -> bool {
+        let silent = |envelope: Envelope| {
+            envelope
+                .scale_time(self.key_follow.time_factor(key_number))
+                .amplitude(key, time)
+                .abs()
+                < f64::EPSILON
+        };
+        let mut zones = self
+            .zones
+            .iter()
+            .filter(|zone| zone.gain(key_number, key.velocity) > 0.)
+            .peekable();
+        if zones.peek().is_none() {
+            return silent(self.envelope);
+        }
+        zones.all(|zone| silent(zone.envelope.unwrap_or(self.envelope)))
+    }
+
+    /// Renders a single key's voice over a whole block and adds it onto `out`,
+    /// `inputs` being the audio input for each frame of the block.
+    /// Every zone the key and its velocity fall into is played with a state of its own, so the
+    /// zones' oscillators keep their slots whichever of them a press plays.
+    pub fn render_voice(
+        &self,
+        key_number: usize,
+        key: &Key,
+        state: &mut State,
+        time: f64,
+        inputs: &[Frame],
+        out: &mut [Frame],
+    ) {
+        let frame_t = 1. / state.sample_rate;
+        let amplitude_factor = self.key_follow.amplitude_factor(key_number);
+        let frequency = Self::frequency(key_number);
+        let mut render =
+            |oscillator: &dyn Oscillator, envelope: Envelope, level: f64, state: &mut State| {
+                let envelope = envelope.scale_time(self.key_follow.time_factor(key_number));
+                for (i, (out, input)) in out.iter_mut().zip(inputs).enumerate() {
+                    let time = time + i as f64 * frame_t;
+                    state.rewind();
+                    state.input = *input;
+                    let amplitude = (envelope.amplitude(key, time) * level) as f32;
+                    let value = oscillator.stereo(frequency, time, state);
+                    *out = frame::add(*out, frame::scale(value, amplitude));
+                }
+            };
+
+        let mut in_zone = false;
+        for (index, zone) in self.zones.iter().enumerate() {
+            let gain = zone.gain(key_number, key.velocity);
+            if gain > 0. {
+                in_zone = true;
+                render(
+                    &*zone.oscillator,
+                    zone.envelope.unwrap_or(self.envelope),
+                    amplitude_factor * gain * zone.volume as f64,
+                    state.zone(index),
+                );
+            }
+        }
+        if !in_zone {
+            render(&*self.oscillator, self.envelope, amplitude_factor, state);
+        }
+    }
+
+    /// Applies the volume and the pan to a block of summed voices.
+    pub fn mix(&self, block: &mut [Frame]) {
+        block
+            .iter_mut()
+            .for_each(|frame| *frame = frame::balance(frame::scale(*frame, self.volume), self.pan));
     }
 }
@@ -1,87 +1,404 @@
 use crate::{
-    key::Key,
-    midi::{ChannelMessageKind, Message, Midi, SystemMessageKind},
-    Data,
+    backend::AudioBackend,
+    engine::{Connection, Engine, Status},
 };
-use jack::{AudioOut, Client, ClientOptions, ClosureProcessHandler, Control, MidiIn, ProcessScope};
-use rayon::prelude::*;
-use std::sync::{Arc, Mutex};
-
-pub fn init(
-    data: Arc<Mutex<Data>>,
-) -> jack::AsyncClient<(), ClosureProcessHandler<impl FnMut(&Client, &ProcessScope) -> Control>> {
-    let (client, _status) = Client::new("rsynth", ClientOptions::NO_START_SERVER)
-        .expect("failed to create jack client!");
-
-    let sample_rate = client.sample_rate();
-    let frame_t = 1. / sample_rate as f64;
-    let mut time = 0.;
-
-    let midi_in = client
-        .register_port("midi_in", MidiIn)
-        .expect("failed to register midi_in port!");
-    let mut audio_out = client
-        .register_port("audio_out", AudioOut)
-        .expect("failed to register audio_out port!");
-
-    let mut keys = [Key {
-        active: false,
-        time_pressed: 0.,
-        time_released: 0.,
-    }; 256];
-
-    let handler = ClosureProcessHandler::new(move |_: &Client, ps: &ProcessScope| -> Control {
-        let reader = midi_in.iter(ps);
-        for v in reader {
-            let midi = Midi::try_from(v.bytes).expect("failed to parse midi event!");
-
-            match midi.message {
-                Message::ChannelMessage { kind, .. } => match kind {
-                    ChannelMessageKind::NoteOff { key_number, .. } => {
-                        let key = &mut keys[key_number as usize];
-                        key.time_released = time;
-                    }
-                    ChannelMessageKind::NoteOn { key_number, .. } => {
-                        let key = &mut keys[key_number as usize];
-                        key.active = true;
-                        key.time_pressed = time;
-                    }
-                    kind => tracing::warn!("unimplemented ChannelMessageKind: {kind:?}"),
-                },
-                Message::SystemMessage { kind } => match kind {
-                    SystemMessageKind::ActiveSensing => (),
-                },
+use jack::{
+    AsyncClient, AudioIn, AudioOut, Client, ClientOptions, ClientStatus, Control, Frames, MidiIn,
+    NotificationHandler, Port, PortFlags, PortId, PortSpec, ProcessHandler, ProcessScope,
+};
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// Sample rate the engine starts with, until the server tells the real one.
+const DEFAULT_SAMPLE_RATE: f64 = 48000.;
+const MIN_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
+/// How often the supervisor checks whether it should stop or reconnect.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How the JACK client presents itself and what it connects to.
+#[derive(clap::Args, Debug)]
+#[command(about = None, long_about = None)]
+#[group(id = "jack_options")]
+pub struct Options {
+    /// Name of the JACK client, give every running instance its own
+    #[arg(long, default_value = "rsynth")]
+    pub client_name: String,
+    /// Output a single mixed-down `audio_out` port instead of `out_left` and `out_right`
+    #[arg(long)]
+    pub mono: bool,
+    /// Register audio input ports (`in_left` and `in_right`, or `audio_in` with `--mono`)
+    #[arg(long)]
+    pub input: bool,
+    /// Connect `midi_in` to every MIDI output port matching this regular expression
+    #[arg(long)]
+    pub midi_source: Option<String>,
+    /// Connect the outputs to the ports matching `--playback`
+    #[arg(long)]
+    pub connect: bool,
+    /// Regular expression for the ports `--connect` connects the outputs to
+    #[arg(long, default_value = "system:playback_.*")]
+    pub playback: String,
+}
+
+/// Connections between one of our ports and another port, source first.
+/// They're remembered so they can be restored after reconnecting.
+type Connections = Arc<Mutex<BTreeSet<(String, String)>>>;
+
+struct Process {
+    engine: Arc<Mutex<Engine>>,
+    status: Arc<Status>,
+    midi_in: Port<MidiIn>,
+    inputs: Vec<Port<AudioIn>>,
+    out_left: Port<AudioOut>,
+    out_right: Option<Port<AudioOut>>,
+}
+
+impl ProcessHandler for Process {
+    fn process(&mut self, _: &Client, ps: &ProcessScope) -> Control {
+        let Self {
+            engine,
+            midi_in,
+            inputs,
+            out_left,
+            out_right,
+            ..
+        } = self;
+        let left = out_left.as_mut_slice(ps);
+        let right = out_right.as_mut().map(|port| port.as_mut_slice(ps));
+
+        // besides this callback, the engine is only locked while no client is running, so this
+        // practically never fails and it never waits
+        match engine.try_lock() {
+            Ok(mut engine) => {
+                let inputs =
+                    [inputs.first(), inputs.get(1)].map(|port| port.map(|port| port.as_slice(ps)));
+                let events = midi_in.iter(ps).map(|v| (v.time as usize, v.bytes));
+                engine.process(events, left, right, inputs);
+            }
+            Err(_) => {
+                left.fill(0.);
+                if let Some(right) = right {
+                    right.fill(0.);
+                }
             }
         }
 
-        let audio_slice = audio_out.as_mut_slice(ps);
-        let instrument = &data.lock().expect("failed to acquire lock!").instrument;
+        Control::Continue
+    }
 
-        keys.iter_mut()
-            .filter(|key| {
-                key.active
-                    && !key.is_pressed()
-                    && instrument.envelope.amplitude(key, time).abs() < f64::EPSILON
-            })
-            .for_each(|key| key.active = false);
+    fn buffer_size(&mut self, _: &Client, size: Frames) -> Control {
+        self.status.buffer_size.store(size, Ordering::Relaxed);
+        self.status.should_redraw.store(true, Ordering::Relaxed);
+        Control::Continue
+    }
+}
 
-        audio_slice.par_iter_mut().enumerate().for_each(|(iv, v)| {
-            *v = instrument.value(&keys, time + iv as f64 * frame_t);
-        });
+struct Notifications {
+    sample_rates: SyncSender<f64>,
+    status: Arc<Status>,
+    shutdown: Arc<AtomicBool>,
+    connections: Connections,
+}
 
-        time += frame_t * audio_slice.len() as f64;
+impl NotificationHandler for Notifications {
+    fn shutdown(&mut self, _status: ClientStatus, _reason: &str) {
+        // runs like a signal handler, so all it does is tell the supervisor
+        self.shutdown.store(true, Ordering::Release);
+    }
 
-        if time >= f64::MAX {
-            time = 0.;
-            keys.iter_mut().for_each(|x| x.active = false);
+    /// Hands the new rate to the engine like patches are, the watcher then rebuilds the
+    /// patches for it once it sees the rate in the status.
+    fn sample_rate(&mut self, _: &Client, sample_rate: Frames) -> Control {
+        match self.sample_rates.try_send(sample_rate as f64) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => (),
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!("too many sample rate changes, ignoring {sample_rate} Hz");
+                return Control::Continue;
+            }
         }
+        self.status
+            .sample_rate
+            .store(sample_rate, Ordering::Release);
+        self.status.should_redraw.store(true, Ordering::Relaxed);
+        Control::Continue
+    }
 
+    fn xrun(&mut self, _: &Client) -> Control {
+        self.status.xruns.fetch_add(1, Ordering::Relaxed);
+        self.status.should_redraw.store(true, Ordering::Relaxed);
         Control::Continue
-    });
+    }
+
+    fn ports_connected(&mut self, client: &Client, a: PortId, b: PortId, are_connected: bool) {
+        let (Some(a), Some(b)) = (client.port_by_id(a), client.port_by_id(b)) else {
+            return;
+        };
+        let (source, destination) = if a.flags().contains(PortFlags::IS_OUTPUT) {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        let (Ok(source), Ok(destination)) = (source.name(), destination.name()) else {
+            return;
+        };
+        let ours = format!("{}:", client.name());
+        if !source.starts_with(&ours) && !destination.starts_with(&ours) {
+            return;
+        }
+        let mut connections = self.connections.lock().expect("failed to acquire lock!");
+        if are_connected {
+            connections.insert((source, destination));
+        } else {
+            connections.remove(&(source, destination));
+        }
+    }
+}
 
-    let active_client = client
-        .activate_async((), handler)
-        .expect("failed to activate jack client!");
+/// Keeps a JACK client running in the background.
+/// Whenever the server goes away, it reconnects with increasing delays and restores the ports
+/// and their connections.
+/// With `mono`, a single `audio_out` port carries both channels mixed down,
+/// otherwise the output goes to `out_left` and `out_right`.
+/// With `input`, audio input ports are registered the same way.
+pub struct JackBackend {
+    options: Option<Options>,
+    channels: usize,
+    status: Arc<Status>,
+    sample_rates: SyncSender<f64>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl JackBackend {
+    /// Sample rate changes are sent through `sample_rates`, see `Engine::set_sample_rates`.
+    pub fn new(options: Options, status: Arc<Status>, sample_rates: SyncSender<f64>) -> Self {
+        Self {
+            channels: if options.mono { 1 } else { 2 },
+            options: Some(options),
+            status,
+            sample_rates,
+            stop: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        }
+    }
+}
 
-    active_client
+impl AudioBackend for JackBackend {
+    /// JACK decides on the sample rate, this is only a guess until the first connection.
+    fn sample_rate(&self) -> f64 {
+        DEFAULT_SAMPLE_RATE
+    }
+
+    fn start(&mut self, engine: Arc<Mutex<Engine>>) {
+        let options = self.options.take().expect("jack backend started twice!");
+        let status = Arc::clone(&self.status);
+        let sample_rates = self.sample_rates.clone();
+        let stop = Arc::clone(&self.stop);
+        self.thread = Some(
+            thread::Builder::new()
+                .name("rsynth-jack".to_string())
+                .spawn(move || supervise(engine, status, sample_rates, options, stop))
+                .expect("failed to spawn jack thread!"),
+        );
+    }
+
+    /// Deactivates the client and stops reconnecting.
+    fn stop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            thread.join().expect("failed to join jack thread!");
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+    }
+
+    /// MIDI arrives on the `midi_in` port.
+    fn has_event_input(&self) -> bool {
+        true
+    }
+
+    fn output_channels(&self) -> usize {
+        self.channels
+    }
+}
+
+fn supervise(
+    engine: Arc<Mutex<Engine>>,
+    status: Arc<Status>,
+    sample_rates: SyncSender<f64>,
+    options: Options,
+    stop: Arc<AtomicBool>,
+) {
+    let connections = Connections::default();
+    let mut backoff = MIN_BACKOFF;
+
+    while !stop.load(Ordering::Acquire) {
+        status.set_connection(Connection::Connecting);
+        match activate(&engine, &status, &sample_rates, &options, &connections) {
+            Ok((active_client, shutdown)) => {
+                status.set_connection(Connection::Connected);
+                backoff = MIN_BACKOFF;
+                while !stop.load(Ordering::Acquire) && !shutdown.load(Ordering::Acquire) {
+                    thread::sleep(POLL_INTERVAL);
+                }
+                if !shutdown.load(Ordering::Acquire) {
+                    active_client
+                        .deactivate()
+                        .expect("failed to deactivate jack client!");
+                    status.set_connection(Connection::Disconnected);
+                    return;
+                }
+                tracing::warn!("jack server shut down, reconnecting");
+                // closing a client is still supported after its server shut down, and it frees
+                // the client together with its callbacks
+                if let Err(err) = active_client.deactivate() {
+                    tracing::warn!("failed to close the jack client after shutdown: {err}");
+                }
+            }
+            Err(err) => {
+                tracing::warn!("failed to connect to jack, retrying in {backoff:?}: {err}")
+            }
+        }
+        status.set_connection(Connection::Disconnected);
+
+        let mut waited = Duration::ZERO;
+        while waited < backoff && !stop.load(Ordering::Acquire) {
+            thread::sleep(POLL_INTERVAL);
+            waited += POLL_INTERVAL;
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Creates the client, registers the ports, activates it and connects it.
+/// Returns the client and the flag its shutdown notification sets.
+fn activate(
+    engine: &Arc<Mutex<Engine>>,
+    status: &Arc<Status>,
+    sample_rates: &SyncSender<f64>,
+    options: &Options,
+    connections: &Connections,
+) -> Result<(AsyncClient<Notifications, Process>, Arc<AtomicBool>), jack::Error> {
+    let (client, _status) = Client::new(&options.client_name, ClientOptions::NO_START_SERVER)?;
+
+    engine
+        .lock()
+        .expect("failed to acquire lock!")
+        .set_sample_rate(client.sample_rate() as f64);
+    status
+        .sample_rate
+        .store(client.sample_rate() as u32, Ordering::Release);
+    status
+        .buffer_size
+        .store(client.buffer_size(), Ordering::Relaxed);
+
+    let midi_in = client.register_port("midi_in", MidiIn)?;
+    let inputs = match (options.input, options.mono) {
+        (false, _) => Vec::new(),
+        (true, true) => vec!["audio_in"],
+        (true, false) => vec!["in_left", "in_right"],
+    }
+    .into_iter()
+    .map(|name| client.register_port(name, AudioIn))
+    .collect::<Result<Vec<_>, _>>()?;
+    let (out_left, out_right) = if options.mono {
+        (client.register_port("audio_out", AudioOut)?, None)
+    } else {
+        (
+            client.register_port("out_left", AudioOut)?,
+            Some(client.register_port("out_right", AudioOut)?),
+        )
+    };
+
+    let midi_in_name = midi_in.name()?;
+    let output_names = [Some(&out_left), out_right.as_ref()]
+        .into_iter()
+        .flatten()
+        .map(|port| port.name())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let notifications = Notifications {
+        sample_rates: sample_rates.clone(),
+        status: Arc::clone(status),
+        shutdown: Arc::clone(&shutdown),
+        connections: Arc::clone(connections),
+    };
+    let process = Process {
+        engine: Arc::clone(engine),
+        status: Arc::clone(status),
+        midi_in,
+        inputs,
+        out_left,
+        out_right,
+    };
+    let active_client = client.activate_async(notifications, process)?;
+
+    connect(
+        active_client.as_client(),
+        options,
+        &midi_in_name,
+        &output_names,
+    );
+    let previous = connections.lock().expect("failed to acquire lock!").clone();
+    for (source, destination) in previous {
+        match active_client
+            .as_client()
+            .connect_ports_by_name(&source, &destination)
+        {
+            Ok(()) | Err(jack::Error::PortAlreadyConnected(..)) => (),
+            Err(err) => tracing::warn!("failed to restore {source} -> {destination}: {err}"),
+        }
+    }
+
+    Ok((active_client, shutdown))
+}
+
+/// Connects the ports chosen in `options`, only warning about connections that fail.
+/// A mono output is connected to the first two playback ports, stereo outputs to one each.
+fn connect(client: &Client, options: &Options, midi_in: &str, outputs: &[String]) {
+    let connect =
+        |source: &str, destination: &str| match client.connect_ports_by_name(source, destination) {
+            Ok(()) | Err(jack::Error::PortAlreadyConnected(..)) => (),
+            Err(err) => tracing::warn!("failed to connect {source} to {destination}: {err}"),
+        };
+
+    if let Some(pattern) = &options.midi_source {
+        let sources = client.ports(
+            Some(pattern),
+            Some(MidiIn.jack_port_type()),
+            PortFlags::IS_OUTPUT,
+        );
+        if sources.is_empty() {
+            tracing::warn!("no midi source matches {pattern}");
+        }
+        sources.iter().for_each(|source| connect(source, midi_in));
+    }
+
+    if options.connect {
+        let playback = client.ports(
+            Some(&options.playback),
+            Some(AudioIn.jack_port_type()),
+            PortFlags::IS_INPUT,
+        );
+        if playback.is_empty() {
+            tracing::warn!("no playback port matches {}", options.playback);
+        }
+        for (i, destination) in playback.iter().take(2).enumerate() {
+            connect(&outputs[i % outputs.len()], destination);
+        }
+    }
 }
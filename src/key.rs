@@ -3,6 +3,7 @@ pub struct Key {
     pub active: bool,
     pub time_pressed: f64,
     pub time_released: f64,
+    pub velocity: u8,
 }
 
 impl Key {
@@ -1,56 +1,208 @@
+mod backend;
+mod effect;
+mod engine;
 mod envelope;
+mod event;
+mod frame;
 mod hz;
 mod instrument;
 mod jack;
 mod key;
+mod master;
 mod midi;
+mod midi_input;
 mod osc;
+mod pool;
+mod record;
+mod session;
+mod shape;
+mod smf;
+mod state;
 mod ui;
 mod watcher;
+mod zone;
 
-use crate::instrument::Instrument;
+use crate::{
+    engine::{Engine, Part, Patch, Reporter, Status},
+    event::EventSource,
+    instrument::Instrument,
+    session::{Session, SessionReadError},
+};
 use clap::Parser;
 use instrument::InstrumentReadError;
-use notify::Watcher;
 use std::{
-    path::Path,
-    sync::{Arc, Mutex},
+    fs::File,
+    path::PathBuf,
+    sync::{atomic::Ordering, mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
 };
 
+/// Patches that can be waiting for the audio thread, or waiting to be freed, at once.
+const PATCH_QUEUE_LENGTH: usize = 4;
+
+/// Sample rate changes that can be waiting for the audio thread at once.
+const SAMPLE_RATE_QUEUE_LENGTH: usize = 4;
+
+/// File the log goes into while the terminal UI runs, unless `--log` says otherwise.
+const LOG_PATH: &str = "rsynth.log";
+
+/// Data shared between the UI and the watcher, never touched by the audio thread.
 pub struct Data {
-    pub should_redraw: bool,
-    pub instrument: Instrument,
+    /// Points of the oscillator's waveform, drawn by the UI.
+    pub preview: Vec<(f64, f64)>,
 }
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    instrument_path: String,
+    /// Instrument played on every MIDI channel
+    #[arg(required_unless_present = "session")]
+    instrument_path: Option<PathBuf>,
+    /// Session file assigning instruments to MIDI channels, instead of a single instrument
+    #[arg(long, conflicts_with = "instrument_path")]
+    session: Option<PathBuf>,
+    /// Extra threads rendering voices alongside the audio thread
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+    /// Run without the terminal UI until the backend finishes
+    #[arg(long)]
+    headless: bool,
+    /// File to log into, by default rsynth.log with the UI and stderr when headless
+    #[arg(long)]
+    log: Option<PathBuf>,
+    #[command(flatten)]
+    backend: backend::Options,
+    #[command(flatten)]
+    jack: jack::Options,
+    #[command(flatten)]
+    midi_input: midi_input::Options,
+    #[command(flatten)]
+    record: record::Options,
 }
 
 fn main() {
     let args = Args::parse();
+    init_logging(&args);
 
-    let data = Arc::new(Mutex::new(Data {
-        should_redraw: true,
-        instrument: Instrument::read(&args.instrument_path).unwrap_or_else(|err| match err {
-            InstrumentReadError::IoError(err) => panic!("failed to read instrument!\n{err:?}"),
-            InstrumentReadError::Deserialize(err) => {
-                panic!("failed to deserialize instrument!\n{err:?}")
+    let session = match (&args.session, &args.instrument_path) {
+        (Some(path), _) => Session::read(path).unwrap_or_else(|err| match err {
+            SessionReadError::IoError(err) => panic!("failed to read session!\n{err:?}"),
+            SessionReadError::Deserialize(err) => {
+                panic!("failed to deserialize session!\n{err:?}")
+            }
+            SessionReadError::InvalidChannel(channel) => {
+                panic!("invalid channel {channel} in session, channels go from 1 to 16!")
             }
         }),
+        (None, Some(path)) => Session::single(path.clone()),
+        (None, None) => unreachable!(),
+    };
+    let instruments = session
+        .parts
+        .iter()
+        .map(|part| {
+            Instrument::read(&part.instrument).unwrap_or_else(|err| match err {
+                InstrumentReadError::IoError(err) => panic!("failed to read instrument!\n{err:?}"),
+                InstrumentReadError::Deserialize(err) => {
+                    panic!("failed to deserialize instrument!\n{err:?}")
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    let data = Arc::new(Mutex::new(Data {
+        preview: instruments.first().map(ui::preview).unwrap_or_default(),
     }));
+    let status = Arc::new(Status::default());
+    let (patch_sender, patch_receiver) = mpsc::sync_channel(PATCH_QUEUE_LENGTH);
+    let (garbage_sender, garbage_receiver) = mpsc::sync_channel(PATCH_QUEUE_LENGTH);
 
-    let mut watcher = watcher::init(Arc::clone(&data), args.instrument_path.clone());
-    let active_client = jack::init(Arc::clone(&data));
+    let (sample_rate_sender, sample_rate_receiver) = mpsc::sync_channel(SAMPLE_RATE_QUEUE_LENGTH);
+    let mut backend = backend::create(
+        args.backend,
+        args.jack,
+        Arc::clone(&status),
+        sample_rate_sender,
+    );
+    let sources = midi_input::create(&args.midi_input);
+    let source = (!sources.is_empty()).then(|| Box::new(sources) as Box<dyn EventSource>);
+    if source.is_none() && !backend.has_event_input() {
+        tracing::warn!(
+            "the backend has no midi input and no other source is set, nothing will play"
+        );
+    }
+    let sample_rate = backend.sample_rate();
+    tracing::info!(
+        "rendering {} channels at {sample_rate} Hz",
+        backend.output_channels()
+    );
+    status
+        .sample_rate
+        .store(sample_rate as u32, Ordering::Relaxed);
+    let parts = session
+        .parts
+        .iter()
+        .zip(instruments)
+        .map(|(part, instrument)| Part::new(part, Patch::new(instrument, sample_rate)))
+        .collect();
+    let mut engine = Engine::new(
+        parts,
+        sample_rate,
+        patch_receiver,
+        garbage_sender,
+        Arc::clone(&status),
+        args.threads,
+        source,
+    );
+    engine.set_sample_rates(sample_rate_receiver);
+    if let Some(master) = session.master {
+        engine.set_master(master);
+    }
+    let (midi_capture, midi_writer) = record::midi(&args.record, &status);
+    engine.set_midi_capture(midi_capture);
+    let (audio_capture, audio_writer) = record::audio(&args.record, &status);
+    engine.set_audio_capture(audio_capture);
+    backend.start(Arc::new(Mutex::new(engine)));
+    let watcher = watcher::init(
+        Arc::clone(&data),
+        Arc::clone(&status),
+        patch_sender,
+        garbage_receiver,
+        session
+            .parts
+            .into_iter()
+            .map(|part| part.instrument)
+            .collect(),
+    );
 
-    ui::run(Arc::clone(&data));
+    if args.headless {
+        let mut reporter = Reporter::default();
+        while backend.is_running() {
+            reporter.report(&status);
+            thread::sleep(Duration::from_millis(100));
+        }
+    } else {
+        ui::run(Arc::clone(&data), Arc::clone(&status));
+    }
 
-    active_client
-        .deactivate()
-        .expect("failed to deactivate jack client!");
+    backend.stop();
+    midi_writer.finish();
+    audio_writer.finish();
+
+    // unwatches every instrument
+    drop(watcher);
+}
 
-    watcher
-        .unwatch(&Path::new(&args.instrument_path))
-        .expect("failed to unwatch file!");
+/// Installs the logger, writing into a file unless the terminal is free because there's no UI.
+fn init_logging(args: &Args) {
+    let path = match (&args.log, args.headless) {
+        (Some(path), _) => path.clone(),
+        (None, true) => return tracing_subscriber::fmt().init(),
+        (None, false) => PathBuf::from(LOG_PATH),
+    };
+    let file = File::create(path).expect("failed to create log file!");
+    tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(Mutex::new(file))
+        .init();
 }
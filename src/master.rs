@@ -0,0 +1,182 @@
+use crate::frame::{self, Frame};
+use serde::{Deserialize, Serialize};
+
+const DC_BLOCKER_POLE: f32 = 0.995;
+
+/// Master section, applied after the effects.
+/// `dc_blocker` removes any constant offset from the signal.
+/// `auto_gain` divides the signal by the square root of the number of sounding keys,
+/// so chords don't get much louder than single notes.
+/// `limiter` keeps the output below its threshold, set it to `null` to disable it.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Master {
+    pub dc_blocker: bool,
+    pub auto_gain: bool,
+    pub limiter: Option<Limiter>,
+    #[serde(skip)]
+    dc: [(f32, f32); 2],
+    #[serde(skip)]
+    gain: f32,
+    #[serde(skip)]
+    gain_step: f32,
+}
+
+impl Default for Master {
+    fn default() -> Self {
+        Self {
+            dc_blocker: true,
+            auto_gain: false,
+            limiter: Some(Limiter::default()),
+            dc: [(0., 0.); 2],
+            gain: 1.,
+            gain_step: 0.,
+        }
+    }
+}
+
+/// Lookahead limiter with a soft knee.
+/// `threshold` and `knee` are in dBFS and dB, `lookahead` and `release` in seconds.
+/// The signal is delayed by `lookahead`, so the gain is already down when a peak arrives.
+/// Both channels share the same gain, so the stereo image doesn't shift.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Limiter {
+    pub threshold: f32,
+    pub knee: f32,
+    pub lookahead: f64,
+    pub release: f64,
+    #[serde(skip)]
+    delay: Vec<Frame>,
+    #[serde(skip)]
+    gains: Vec<f32>,
+    #[serde(skip)]
+    index: usize,
+    #[serde(skip)]
+    gain: f32,
+    #[serde(skip)]
+    release_coefficient: f32,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self {
+            threshold: -0.3,
+            knee: 3.,
+            lookahead: 0.005,
+            release: 0.1,
+            delay: Vec::new(),
+            gains: Vec::new(),
+            index: 0,
+            gain: 1.,
+            release_coefficient: 0.,
+        }
+    }
+}
+
+impl Limiter {
+    /// Reuses the buffers of earlier calls, so it only allocates for a rate higher than any
+    /// before.
+    fn prepare(&mut self, sample_rate: f64) {
+        let length = ((self.lookahead * sample_rate) as usize).max(1);
+        self.delay.clear();
+        self.delay.resize(length, [0., 0.]);
+        self.gains.clear();
+        self.gains.resize(length, 1.);
+        self.index = 0;
+        self.gain = 1.;
+        self.release_coefficient = (1. - (-1. / (self.release * sample_rate)).exp()) as f32;
+    }
+
+    /// Returns the gain needed to bring a sample below the threshold, in linear units.
+    fn gain_computer(&self, sample: f32) -> f32 {
+        let level = 20. * sample.abs().max(f32::MIN_POSITIVE).log10();
+        let overshoot = level - self.threshold;
+        // a knee of zero is a hard knee, which the soft knee's formula would divide by
+        let knee = self.knee.max(f32::EPSILON);
+        let reduction = if 2. * overshoot < -knee {
+            0.
+        } else if 2. * overshoot > knee {
+            -overshoot
+        } else {
+            -(overshoot + knee / 2.).powi(2) / (2. * knee)
+        };
+        10f32.powf(reduction / 20.)
+    }
+
+    fn process(&mut self, frame: Frame) -> Frame {
+        if self.delay.is_empty() {
+            return frame;
+        }
+        let delayed = self.delay[self.index];
+        self.delay[self.index] = frame;
+        self.gains[self.index] = self.gain_computer(frame[0].abs().max(frame[1].abs()));
+        self.index = (self.index + 1) % self.delay.len();
+
+        let target = self.gains.iter().copied().fold(1., f32::min);
+        if target < self.gain {
+            self.gain = target;
+        } else {
+            self.gain += (target - self.gain) * self.release_coefficient;
+        }
+        frame::scale(delayed, self.gain)
+    }
+}
+
+impl Master {
+    /// Prepares for a sample rate, only allocating for a rate higher than any before.
+    pub fn prepare(&mut self, sample_rate: f64) {
+        self.dc = [(0., 0.); 2];
+        self.gain = 1.;
+        // reach a new voice count's gain in about 10ms
+        self.gain_step = (1. / (0.01 * sample_rate)) as f32;
+        if let Some(limiter) = &mut self.limiter {
+            limiter.prepare(sample_rate);
+        }
+    }
+
+    /// Processes a single frame, `voices` being the number of keys currently sounding.
+    pub fn process(&mut self, frame: Frame, voices: usize) -> Frame {
+        let mut frame = frame;
+
+        if self.dc_blocker {
+            for (sample, (x1, y1)) in frame.iter_mut().zip(&mut self.dc) {
+                let y = *sample - *x1 + DC_BLOCKER_POLE * *y1;
+                (*x1, *y1) = (*sample, y);
+                *sample = y;
+            }
+        }
+
+        if self.auto_gain {
+            let target = 1. / (voices.max(1) as f32).sqrt();
+            self.gain += (target - self.gain) * self.gain_step.min(1.);
+            frame = frame::scale(frame, self.gain);
+        }
+
+        match &mut self.limiter {
+            Some(limiter) => limiter.process(frame),
+            None => frame,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hard_knee() {
+        let limiter = Limiter {
+            threshold: -6.,
+            knee: 0.,
+            ..Default::default()
+        };
+        let threshold = 10f32.powf(-6. / 20.);
+        for sample in [0., 0.1, threshold, 0.9, 1., 2.] {
+            let gain = limiter.gain_computer(sample);
+            assert!(gain.is_finite() && gain <= 1., "{sample}: {gain}");
+            assert!(sample * gain <= threshold * 1.0001, "{sample}: {gain}");
+        }
+        assert_eq!(limiter.gain_computer(0.1), 1.);
+    }
+}
@@ -22,7 +22,17 @@ pub enum ChannelMessageKind {
 
 #[derive(Debug)]
 pub enum SystemMessageKind {
+    SystemExclusive,
+    MtcQuarterFrame,
+    SongPositionPointer,
+    SongSelect,
+    TuneRequest,
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
     ActiveSensing,
+    SystemReset,
 }
 
 fn note_number_to_human(num: u8) -> String {
@@ -59,22 +69,136 @@ pub enum ParseError {
     ByteSequenceTooLong,
     InvalidStatusByte,
     UnimplementedStatusByte(u8),
+    /// The message is shorter than its status byte requires.
+    MissingDataByte,
+    /// A data byte has its top bit set.
+    InvalidDataByte,
+    /// A system exclusive message doesn't end with `0xF7`.
+    UnterminatedSystemExclusive,
+}
+
+/// Returns the data byte at `index`, which has to be there and below 128.
+fn data_byte(value: &[u8], index: usize) -> Result<u8, ParseError> {
+    match (value.get(index), value.first()) {
+        (Some(&byte), _) if byte >> 7 == 0 => Ok(byte),
+        (Some(_), _) => Err(ParseError::InvalidDataByte),
+        (None, Some(_)) => Err(ParseError::MissingDataByte),
+        (None, None) => Err(ParseError::NoData),
+    }
+}
+
+/// Returns the number of data bytes following a status byte, except for system exclusive.
+fn data_length(status: u8) -> usize {
+    match status {
+        0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+        0x80..=0xEF | 0xF2 => 2,
+        _ => 0,
+    }
+}
+
+/// A message of up to three bytes, which can be passed around without allocating.
+#[derive(Clone, Copy, Debug)]
+pub struct ShortMessage {
+    bytes: [u8; 3],
+    len: usize,
+}
+
+impl ShortMessage {
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut message = Self {
+            bytes: [0; 3],
+            len: bytes.len().min(3),
+        };
+        message.bytes[..message.len].copy_from_slice(&bytes[..message.len]);
+        message
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Splits a raw MIDI byte stream, like the one of a MIDI device, into messages.
+/// Handles running status and real-time messages in the middle of other messages.
+/// System exclusive messages are skipped, they don't affect the sound.
+#[derive(Default)]
+pub struct StreamParser {
+    status: Option<u8>,
+    data: [u8; 2],
+    len: usize,
+    system_exclusive: bool,
+}
+
+impl StreamParser {
+    /// Feeds a single byte, returning the message it completes, if any.
+    pub fn feed(&mut self, byte: u8) -> Option<ShortMessage> {
+        match byte {
+            // real-time messages may appear anywhere and don't affect running status
+            0xF8..=0xFF => return Some(ShortMessage::new(&[byte])),
+            0x80..=0xF7 => {
+                self.system_exclusive = byte == 0xF0;
+                self.len = 0;
+                self.status = None;
+                match byte {
+                    0xF0 | 0xF7 => (),
+                    _ if data_length(byte) == 0 => return Some(ShortMessage::new(&[byte])),
+                    _ => self.status = Some(byte),
+                }
+                return None;
+            }
+            _ => (),
+        }
+
+        let status = self.status.filter(|_| !self.system_exclusive)?;
+        self.data[self.len] = byte;
+        self.len += 1;
+        if self.len < data_length(status) {
+            return None;
+        }
+        self.len = 0;
+        // only channel messages have running status
+        if status >= 0xF0 {
+            self.status = None;
+        }
+        let mut bytes = [status, 0, 0];
+        bytes[1..=data_length(status)].copy_from_slice(&self.data[..data_length(status)]);
+        Some(ShortMessage::new(&bytes[..=data_length(status)]))
+    }
 }
 
 impl TryFrom<&[u8]> for Midi {
     type Error = ParseError;
 
+    /// Parses a single complete message, never panicking on malformed input.
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        if value.len() == 0 {
+        let Some(&status) = value.first() else {
             return Err(ParseError::NoData);
+        };
+        if status >> 7 != 1 {
+            return Err(ParseError::InvalidStatusByte);
         }
-        if value.len() > 3 {
-            return Err(ParseError::ByteSequenceTooLong);
+
+        if status == 0xF0 {
+            if value.last() != Some(&0xF7) || value.len() < 2 {
+                return Err(ParseError::UnterminatedSystemExclusive);
+            }
+            if value[1..value.len() - 1].iter().any(|byte| byte >> 7 != 0) {
+                return Err(ParseError::InvalidDataByte);
+            }
+            return Ok(Self {
+                message: Message::SystemMessage {
+                    kind: SystemMessageKind::SystemExclusive,
+                },
+                channel: 0,
+            });
         }
 
-        let status = value[0];
-        if status >> 7 != 1 {
-            return Err(ParseError::InvalidStatusByte);
+        let length = data_length(status);
+        for index in 1..=length {
+            data_byte(value, index)?;
+        }
+        if value.len() > length + 1 {
+            return Err(ParseError::ByteSequenceTooLong);
         }
 
         let message = match status >> 4 {
@@ -82,12 +206,12 @@ impl TryFrom<&[u8]> for Midi {
                 channel: status & 0xF,
                 kind: match status >> 4 {
                     0x8 => ChannelMessageKind::NoteOff {
-                        key_number: value[1],
-                        velocity: value[2],
+                        key_number: data_byte(value, 1)?,
+                        velocity: data_byte(value, 2)?,
                     },
                     0x9 => ChannelMessageKind::NoteOn {
-                        key_number: value[1],
-                        velocity: value[2],
+                        key_number: data_byte(value, 1)?,
+                        velocity: data_byte(value, 2)?,
                     },
                     0xA => ChannelMessageKind::PolyphonicKeyPressure,
                     0xB => ChannelMessageKind::ControlChange,
@@ -99,7 +223,16 @@ impl TryFrom<&[u8]> for Midi {
             },
             0xF => Message::SystemMessage {
                 kind: match status & 0xF {
+                    0x1 => SystemMessageKind::MtcQuarterFrame,
+                    0x2 => SystemMessageKind::SongPositionPointer,
+                    0x3 => SystemMessageKind::SongSelect,
+                    0x6 => SystemMessageKind::TuneRequest,
+                    0x8 => SystemMessageKind::TimingClock,
+                    0xA => SystemMessageKind::Start,
+                    0xB => SystemMessageKind::Continue,
+                    0xC => SystemMessageKind::Stop,
                     0xE => SystemMessageKind::ActiveSensing,
+                    0xF => SystemMessageKind::SystemReset,
                     _ => return Err(ParseError::UnimplementedStatusByte(status)),
                 },
             },
@@ -110,3 +243,254 @@ impl TryFrom<&[u8]> for Midi {
         Ok(Self { message, channel })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Small xorshift generator, so the fuzz loops are reproducible without extra crates.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Midi, ParseError> {
+        Midi::try_from(bytes)
+    }
+
+    /// Feeds `bytes` to a fresh parser in chunks ending at `splits`, like reads from a device.
+    fn feed_chunks(bytes: &[u8], splits: &[usize]) -> Vec<Vec<u8>> {
+        let mut parser = StreamParser::default();
+        let mut messages = Vec::new();
+        let mut start = 0;
+        for &end in splits.iter().chain([&bytes.len()]) {
+            for &byte in &bytes[start..end] {
+                messages.extend(parser.feed(byte).map(|message| message.as_bytes().to_vec()));
+            }
+            start = end;
+        }
+        messages
+    }
+
+    #[test]
+    fn empty_input() {
+        assert!(matches!(parse(&[]), Err(ParseError::NoData)));
+    }
+
+    #[test]
+    fn truncated_input() {
+        assert!(matches!(parse(&[0x90]), Err(ParseError::MissingDataByte)));
+        assert!(matches!(
+            parse(&[0x90, 60]),
+            Err(ParseError::MissingDataByte)
+        ));
+        assert!(matches!(parse(&[0xC0]), Err(ParseError::MissingDataByte)));
+        assert!(matches!(
+            parse(&[0xF2, 1]),
+            Err(ParseError::MissingDataByte)
+        ));
+        assert!(matches!(
+            parse(&[0xF0, 1, 2]),
+            Err(ParseError::UnterminatedSystemExclusive)
+        ));
+    }
+
+    #[test]
+    fn data_bytes_only() {
+        assert!(matches!(parse(&[60]), Err(ParseError::InvalidStatusByte)));
+        assert!(matches!(
+            parse(&[60, 100]),
+            Err(ParseError::InvalidStatusByte)
+        ));
+        assert!(matches!(
+            parse(&[0x90, 60, 0x80]),
+            Err(ParseError::InvalidDataByte)
+        ));
+    }
+
+    #[test]
+    fn too_long() {
+        assert!(matches!(
+            parse(&[0x90, 60, 100, 1]),
+            Err(ParseError::ByteSequenceTooLong)
+        ));
+        assert!(matches!(
+            parse(&[0xF8, 0]),
+            Err(ParseError::ByteSequenceTooLong)
+        ));
+    }
+
+    #[test]
+    fn channel_messages() {
+        let midi = parse(&[0x93, 60, 100]).unwrap();
+        assert_eq!(midi.channel, 3);
+        assert!(matches!(
+            midi.message,
+            Message::ChannelMessage {
+                channel: 3,
+                kind: ChannelMessageKind::NoteOn {
+                    key_number: 60,
+                    velocity: 100
+                }
+            }
+        ));
+        assert!(matches!(
+            parse(&[0x80, 60, 0]).unwrap().message,
+            Message::ChannelMessage {
+                kind: ChannelMessageKind::NoteOff { key_number: 60, .. },
+                ..
+            }
+        ));
+        assert!(parse(&[0xB0, 1, 64]).is_ok());
+        assert!(parse(&[0xC0, 5]).is_ok());
+        assert!(parse(&[0xE0, 0, 64]).is_ok());
+    }
+
+    #[test]
+    fn system_exclusive() {
+        assert!(matches!(
+            parse(&[0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7])
+                .unwrap()
+                .message,
+            Message::SystemMessage {
+                kind: SystemMessageKind::SystemExclusive
+            }
+        ));
+        assert!(matches!(
+            parse(&[0xF0, 0xF7]).unwrap().message,
+            Message::SystemMessage {
+                kind: SystemMessageKind::SystemExclusive
+            }
+        ));
+        assert!(matches!(
+            parse(&[0xF0]),
+            Err(ParseError::UnterminatedSystemExclusive)
+        ));
+        assert!(matches!(
+            parse(&[0xF0, 0x90, 0xF7]),
+            Err(ParseError::InvalidDataByte)
+        ));
+    }
+
+    #[test]
+    fn real_time_messages() {
+        for (byte, kind) in [
+            (0xF8, SystemMessageKind::TimingClock),
+            (0xFA, SystemMessageKind::Start),
+            (0xFB, SystemMessageKind::Continue),
+            (0xFC, SystemMessageKind::Stop),
+            (0xFE, SystemMessageKind::ActiveSensing),
+            (0xFF, SystemMessageKind::SystemReset),
+        ] {
+            let Message::SystemMessage { kind: parsed } = parse(&[byte]).unwrap().message else {
+                panic!("{byte:#X} isn't a system message");
+            };
+            assert_eq!(format!("{parsed:?}"), format!("{kind:?}"));
+        }
+        for byte in [0xF4, 0xF5, 0xF9, 0xFD] {
+            assert!(matches!(
+                parse(&[byte]),
+                Err(ParseError::UnimplementedStatusByte(status)) if status == byte
+            ));
+        }
+    }
+
+    #[test]
+    fn never_panics_on_arbitrary_bytes() {
+        // every input of up to two bytes, and up to three with a status byte first
+        parse(&[]).ok();
+        for a in 0..=255 {
+            parse(&[a]).ok();
+            for b in 0..=255 {
+                parse(&[a, b]).ok();
+            }
+        }
+        for a in 0x80..=0xFF {
+            for b in 0..=255 {
+                for c in [0, 1, 0x40, 0x7F, 0x80, 0xF7, 0xFF] {
+                    parse(&[a, b, c]).ok();
+                }
+            }
+        }
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        for _ in 0..100_000 {
+            let len = rng.below(16);
+            let mut bytes = rng.bytes(len);
+            // system exclusive has the most paths, so start with it now and then
+            if len > 0 && rng.below(4) == 0 {
+                bytes[0] = 0xF0;
+            }
+            parse(&bytes).ok();
+        }
+    }
+
+    #[test]
+    fn stream_parser_splits_messages() {
+        let stream = [
+            0x90, 60, 100, // note on
+            61, 101,  // running status
+            0xF8, // clock in between messages
+            62, 0xF8, 102, // clock in the middle of a message
+            0xF0, 1, 2, 0xF7, // system exclusive, cancels running status
+            63, 64, // no status
+            0xC1, 5, 6, // program change with running status
+            0xF2, 1, 2, // song position
+            3, 4, // system common messages have no running status
+        ];
+        let expected: Vec<Vec<u8>> = vec![
+            vec![0x90, 60, 100],
+            vec![0x90, 61, 101],
+            vec![0xF8],
+            vec![0xF8],
+            vec![0x90, 62, 102],
+            vec![0xC1, 5],
+            vec![0xC1, 6],
+            vec![0xF2, 1, 2],
+        ];
+        for split in 0..=stream.len() {
+            assert_eq!(feed_chunks(&stream, &[split]), expected, "split at {split}");
+        }
+        for message in &expected {
+            assert!(parse(message).is_ok());
+        }
+    }
+
+    #[test]
+    fn stream_parser_never_panics_and_ignores_splits() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..10_000 {
+            let len = rng.below(64);
+            let stream = rng.bytes(len);
+            let whole = feed_chunks(&stream, &[]);
+            let mut splits = (0..rng.below(8))
+                .map(|_| rng.below(len + 1))
+                .collect::<Vec<_>>();
+            splits.sort();
+            assert_eq!(feed_chunks(&stream, &splits), whole);
+
+            for message in &whole {
+                assert!((1..=3).contains(&message.len()));
+                assert!(message[0] >> 7 == 1 && message[0] != 0xF0 && message[0] != 0xF7);
+                assert!(message[1..].iter().all(|byte| byte >> 7 == 0));
+                // complete messages of known status bytes always parse
+                if !matches!(message[0], 0xF4 | 0xF5 | 0xF9 | 0xFD) {
+                    assert!(parse(message).is_ok(), "{message:X?}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use crate::{
+    event::{EventSource, Events},
+    midi::{ShortMessage, StreamParser},
+    smf::{Player, Smf, SmfReadError},
+};
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError},
+    thread,
+};
+
+/// Messages that can wait for the engine before new ones get dropped.
+const QUEUE_LENGTH: usize = 1024;
+
+/// Where to read MIDI from, besides the audio backend's own MIDI input.
+#[derive(clap::Args, Debug)]
+#[command(about = None, long_about = None)]
+#[group(id = "midi_input_options")]
+pub struct Options {
+    /// Read MIDI from a raw MIDI device, like `/dev/snd/midiC1D0`
+    #[arg(long)]
+    pub midi_device: Option<PathBuf>,
+    /// Read MIDI from the ALSA sequencer ports at this `client:port` address or whose name
+    /// contains this
+    #[cfg(feature = "alsa")]
+    #[arg(long)]
+    pub alsa_seq: Option<String>,
+    /// Play a standard MIDI file, format 0 or 1
+    #[arg(long)]
+    pub play: Option<PathBuf>,
+}
+
+/// Creates a source for every input chosen in `options`.
+pub fn create(options: &Options) -> Vec<Box<dyn EventSource>> {
+    let mut sources = Vec::<Box<dyn EventSource>>::new();
+    if let Some(path) = &options.midi_device {
+        sources.push(Box::new(raw_midi(path)));
+    }
+    #[cfg(feature = "alsa")]
+    if let Some(pattern) = &options.alsa_seq {
+        sources.push(Box::new(sequencer::open(pattern.clone())));
+    }
+    if let Some(path) = &options.play {
+        let smf = Smf::read(path).unwrap_or_else(|err| match err {
+            SmfReadError::IoError(err) => panic!("failed to read midi file!\n{err:?}"),
+            SmfReadError::Invalid(reason) => panic!("failed to parse midi file: {reason}!"),
+            SmfReadError::UnsupportedFormat(format) => {
+                panic!("midi file has format {format}, only formats 0 and 1 can be played!")
+            }
+        });
+        sources.push(Box::new(Player::new(smf)));
+    }
+    sources
+}
+
+/// Live MIDI, read on a thread of its own.
+/// When exactly a message arrived within the last period isn't known, so every message is
+/// played at the start of the next one.
+/// It's finished once its thread stopped, because the input closed or failed.
+pub struct LiveSource {
+    messages: Receiver<ShortMessage>,
+    finished: bool,
+}
+
+impl EventSource for LiveSource {
+    fn read(&mut self, _frames: usize, _sample_rate: f64, events: &mut Events) {
+        loop {
+            match self.messages.try_recv() {
+                Ok(message) => events.push(0, message.as_bytes()),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.finished = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+/// Spawns a thread that runs `read` with a sender for the messages it reads.
+fn spawn(name: &str, read: impl FnOnce(Sender) + Send + 'static) -> LiveSource {
+    let (sender, messages) = mpsc::sync_channel(QUEUE_LENGTH);
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || read(Sender(sender)))
+        .expect("failed to spawn midi input thread!");
+    LiveSource {
+        messages,
+        finished: false,
+    }
+}
+
+struct Sender(SyncSender<ShortMessage>);
+
+impl Sender {
+    /// Sends a message to the engine, dropping it if the engine fell behind.
+    /// Returns whether the engine is still there.
+    fn send(&self, message: ShortMessage) -> bool {
+        match self.0.try_send(message) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!("midi input queue is full, dropped a message");
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+/// Reads a raw MIDI device, like the ones ALSA creates at `/dev/snd/midiC*D*`.
+fn raw_midi(path: &Path) -> LiveSource {
+    let mut device = File::open(path).expect("failed to open midi device!");
+    let path = path.display().to_string();
+    spawn("rsynth-midi", move |sender| {
+        let mut parser = StreamParser::default();
+        let mut buffer = [0; 256];
+        loop {
+            let read = match device.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) => {
+                    tracing::warn!("failed to read from {path}: {err}");
+                    break;
+                }
+            };
+            for message in buffer[..read].iter().filter_map(|byte| parser.feed(*byte)) {
+                if !sender.send(message) {
+                    return;
+                }
+            }
+        }
+        tracing::warn!("midi device {path} closed");
+    })
+}
+
+#[cfg(feature = "alsa")]
+mod sequencer {
+    use super::{spawn, LiveSource, Sender};
+    use crate::midi::StreamParser;
+    use alsa::seq::{Addr, ClientIter, MidiEvent, PortCap, PortIter, PortSubscribe, PortType, Seq};
+    use std::ffi::CString;
+
+    /// Opens an ALSA sequencer client with a `midi_in` port and connects every readable port
+    /// at the `client:port` address `pattern` or whose client or port name contains it.
+    /// Failing to set the client up is logged and finishes the source.
+    pub fn open(pattern: String) -> LiveSource {
+        spawn("rsynth-alsa-seq", move |sender| {
+            if let Err(err) = run(&pattern, &sender) {
+                tracing::error!("alsa sequencer input failed: {err}");
+            }
+        })
+    }
+
+    /// Reads from the sequencer until it fails or the engine is gone.
+    fn run(pattern: &str, sender: &Sender) -> alsa::Result<()> {
+        let seq = Seq::open(None, Some(alsa::Direction::Capture), false)?;
+        seq.set_client_name(&CString::new("rsynth").expect("invalid client name!"))?;
+        let port = seq.create_simple_port(
+            &CString::new("midi_in").expect("invalid port name!"),
+            PortCap::WRITE | PortCap::SUBS_WRITE,
+            PortType::MIDI_GENERIC | PortType::APPLICATION,
+        )?;
+        let dest = Addr {
+            client: seq.client_id()?,
+            port,
+        };
+
+        let sources = ClientIter::new(&seq)
+            .flat_map(|client| {
+                let client_name = client.get_name().unwrap_or_default().to_string();
+                PortIter::new(&seq, client.get_client())
+                    .map(move |port| (client_name.clone(), port))
+            })
+            .filter(|(client_name, port)| {
+                let address = format!("{}:{}", port.get_client(), port.get_port());
+                port.get_capability()
+                    .contains(PortCap::READ | PortCap::SUBS_READ)
+                    && (address == pattern
+                        || client_name.contains(pattern)
+                        || port.get_name().unwrap_or_default().contains(pattern))
+            })
+            .map(|(_, port)| port.addr())
+            .collect::<Vec<_>>();
+        if sources.is_empty() {
+            tracing::warn!("no alsa sequencer port matches {pattern}");
+        }
+        for source in sources {
+            let subscription = PortSubscribe::empty()?;
+            subscription.set_sender(source);
+            subscription.set_dest(dest);
+            if let Err(err) = seq.subscribe_port(&subscription) {
+                tracing::warn!("failed to connect {}:{}: {err}", source.client, source.port);
+            }
+        }
+
+        let decoder = MidiEvent::new(256)?;
+        decoder.enable_running_status(false);
+        let mut parser = StreamParser::default();
+        let mut buffer = [0; 256];
+        let mut input = seq.input();
+        loop {
+            let mut event = input.event_input()?;
+            // events that aren't MIDI, like port announcements, don't decode to anything
+            let Ok(read) = decoder.decode(&mut buffer, &mut event) else {
+                continue;
+            };
+            for message in buffer[..read].iter().filter_map(|byte| parser.feed(*byte)) {
+                if !sender.send(message) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
@@ -1,19 +1,42 @@
-use crate::hz::Hertz;
+use crate::{
+    frame::{self, Frame},
+    hz::{Hertz, Hz},
+    shape::{self, Curve},
+    state::State,
+};
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 
 /// Oscillator trait.
 /// Is implemented on each oscillator and also on `Vec<Box<dyn Oscillator>>`.
+/// Oscillators are shared between all keys, anything they need to remember between samples
+/// is kept in the key's `State`.
 #[typetag::serde(tag = "type")]
 pub trait Oscillator: Debug + Send + Sync {
     /// Returns a value of an oscillator with a specific frequency at a specific time.
-    fn value(&self, frequency: Hertz<f64>, time: f64) -> f32;
+    /// Stateful oscillators advance by one sample with every call.
+    fn value(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> f32;
+
+    /// Returns a stereo value of an oscillator, see `value`.
+    /// Oscillators are centered unless they override this.
+    fn stereo(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> Frame {
+        let value = self.value(frequency, time, state);
+        [value, value]
+    }
 }
 
 #[typetag::serde]
 impl Oscillator for Vec<Box<dyn Oscillator>> {
-    fn value(&self, frequency: Hertz<f64>, time: f64) -> f32 {
-        self.into_iter().map(|osc| osc.value(frequency, time)).sum()
+    fn value(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> f32 {
+        self.into_iter()
+            .map(|osc| osc.value(frequency, time, state))
+            .sum()
+    }
+
+    fn stereo(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> Frame {
+        self.iter().fold([0., 0.], |sum, osc| {
+            frame::add(sum, osc.stereo(frequency, time, state))
+        })
     }
 }
 
@@ -43,6 +66,69 @@ pub struct Sawtooth {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SawtoothFast;
 
+/// Additive oscillator.
+/// Sums sine waves (partials), each with its own amplitude, phase, frequency ratio and decay.
+/// Partials above the Nyquist frequency are skipped, so they never alias.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Additive {
+    pub partials: Partials,
+}
+
+/// Partials of an `Additive` oscillator.
+/// `List` takes every partial explicitly.
+/// `Formula` generates `count` harmonics with amplitudes of `1 / n^slope`,
+/// optionally odd ones only, each decaying `n` times faster than the fundamental's `decay`.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Partials {
+    List(Vec<Partial>),
+    Formula {
+        count: usize,
+        slope: f32,
+        #[serde(default)]
+        odd_only: bool,
+        #[serde(default)]
+        decay: f64,
+    },
+}
+
+/// A single partial of an `Additive` oscillator.
+/// `ratio` is the partial's frequency relative to the key's, it doesn't have to be a whole number.
+/// `phase` is in radians.
+/// `decay` is the time in seconds for the partial to fade to about a third, 0 never fades.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Partial {
+    pub ratio: f64,
+    pub amplitude: f32,
+    #[serde(default)]
+    pub phase: f64,
+    #[serde(default)]
+    pub decay: f64,
+}
+
+impl Partials {
+    fn for_each(&self, mut f: impl FnMut(&Partial)) {
+        match self {
+            Partials::List(partials) => partials.iter().for_each(f),
+            Partials::Formula {
+                count,
+                slope,
+                odd_only,
+                decay,
+            } => (1..)
+                .filter(|n| !odd_only || n % 2 == 1)
+                .take(*count)
+                .for_each(|n| {
+                    f(&Partial {
+                        ratio: n as f64,
+                        amplitude: (n as f32).powf(-slope),
+                        phase: 0.,
+                        decay: decay / n as f64,
+                    })
+                }),
+        }
+    }
+}
+
 /// Amplitude oscillator.
 /// Adjust the amplitude of an existing oscillator.
 #[derive(Debug, Deserialize, Serialize)]
@@ -51,50 +137,513 @@ pub struct Amplitude {
     pub oscillator: Box<dyn Oscillator>,
 }
 
+/// Unison oscillator.
+/// Plays `voices` copies of an oscillator detuned across a range of `detune` cents.
+/// `curve` shapes how the copies are distributed across that range,
+/// values above 1 pull them towards the center, values below 1 push them outwards.
+/// With `random_phase`, each copy starts at a different (but repeatable) phase.
+/// `spread` from 0 to 1 pans the copies across the stereo field, the most detuned ones furthest out.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Unison {
+    pub voices: usize,
+    pub detune: f64,
+    #[serde(default = "shape::one")]
+    pub curve: f64,
+    #[serde(default)]
+    pub random_phase: bool,
+    #[serde(default)]
+    pub spread: f32,
+    pub oscillator: Box<dyn Oscillator>,
+}
+
+/// Pitch oscillator.
+/// Changes the frequency an existing oscillator is played at.
+/// The key's frequency is transposed by `octaves`, `semitones` and `cents` and then multiplied by `ratio`.
+/// If `fixed` is set, the key is ignored and the oscillator always plays at that frequency.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Pitch {
+    #[serde(default)]
+    pub octaves: f64,
+    #[serde(default)]
+    pub semitones: f64,
+    #[serde(default)]
+    pub cents: f64,
+    #[serde(default = "shape::one")]
+    pub ratio: f64,
+    #[serde(default)]
+    pub fixed: Option<f64>,
+    pub oscillator: Box<dyn Oscillator>,
+}
+
+/// Pan oscillator.
+/// Places an existing oscillator in the stereo field, `pan` going from -1 (left) to 1 (right).
+/// The value of the optional `modulator` is added to `pan`,
+/// wrap it in a `Pitch` with a `fixed` frequency to get an auto-panner.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Pan {
+    pub pan: f32,
+    #[serde(default)]
+    pub modulator: Option<Box<dyn Oscillator>>,
+    pub oscillator: Box<dyn Oscillator>,
+}
+
+/// Audio input oscillator.
+/// Plays whatever comes in through the audio input, ignoring the key's frequency.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Input;
+
+/// Envelope follower oscillator.
+/// Follows the level of the audio input, rising within about `attack` seconds
+/// and falling within about `release` seconds. Goes from 0 upwards, so it's best used
+/// as the modulator of a `RingMod` to impose the input's dynamics on the carrier.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Follower {
+    pub attack: f64,
+    pub release: f64,
+}
+
+/// Ring modulation oscillator.
+/// Multiplies the output of two oscillators.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RingMod {
+    pub carrier: Box<dyn Oscillator>,
+    pub modulator: Box<dyn Oscillator>,
+}
+
+/// Amplitude modulation oscillator.
+/// Scales the carrier by the modulator, `depth` sets how much the amplitude changes.
+/// A depth of 0 leaves the carrier untouched, a depth of 1 fully silences it at the modulator's troughs.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AmplitudeMod {
+    pub depth: f32,
+    pub carrier: Box<dyn Oscillator>,
+    pub modulator: Box<dyn Oscillator>,
+}
+
+/// Hard sync oscillator.
+/// Plays an existing oscillator at `ratio` times the key's frequency,
+/// restarting its phase at every cycle of the key's frequency.
+/// Its type in instrument files is `Sync`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HardSync {
+    pub ratio: f64,
+    pub oscillator: Box<dyn Oscillator>,
+}
+
+/// Plucked string oscillator (Karplus-Strong).
+/// A delay line one period long is filled with a burst of noise and fed back through a lowpass filter.
+/// `damping` is the fraction of energy lost on every round trip, small values ring longer.
+/// `brightness` from 0 to 1 sets how much of the high end survives a round trip.
+/// `pick_position` from 0 to 1 is where along the string it is plucked, 0.5 being the middle.
+/// `release_damping` replaces `damping` once the key is released, muting the string.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Pluck {
+    pub damping: f32,
+    pub brightness: f32,
+    #[serde(default = "pick_position")]
+    pub pick_position: f32,
+    #[serde(default = "release_damping")]
+    pub release_damping: f32,
+}
+
+fn pick_position() -> f32 {
+    0.1
+}
+
+fn release_damping() -> f32 {
+    0.2
+}
+
+#[derive(Default)]
+struct PluckString {
+    buffer: Vec<f32>,
+    index: usize,
+    last: f32,
+    /// Coefficient of the allpass filter making up the fraction of a sample the period is longer
+    /// than the buffer, and its last input and output.
+    allpass: f32,
+    allpass_in: f32,
+    allpass_out: f32,
+}
+
+impl PluckString {
+    /// Fills the string with a new burst of noise, reusing the buffer.
+    fn pluck(&mut self, pluck: &Pluck, frequency: Hertz<f64>, sample_rate: f64, seed: u64) {
+        // the loop filter delays by its second tap's weight, the buffer and the allpass make up
+        // the rest of the period, with the allpass between 0.5 and 1.5 samples where it's flattest
+        let filter_delay = (1. - pluck.brightness as f64) / 2.;
+        let delay = (sample_rate / *frequency - filter_delay).max(2.5);
+        let length = (delay - 0.5).floor() as usize;
+        let fraction = delay - length as f64;
+        self.allpass = ((1. - fraction) / (1. + fraction)) as f32;
+        self.allpass_in = 0.;
+        self.allpass_out = 0.;
+        let noise = |i: usize| 2. * random(seed.wrapping_add(i as u64)) as f32 - 1.;
+        // plucking at a point on the string cancels the harmonics that have a node there,
+        // which is the same as combing the excitation with a copy delayed by the pick position
+        let pick = ((pluck.pick_position.clamp(0., 1.) * length as f32) as usize).max(1);
+        self.buffer.resize(length, 0.);
+        self.buffer.iter_mut().enumerate().for_each(|(i, sample)| {
+            let delayed = if i >= pick { noise(i - pick) } else { 0. };
+            *sample = (noise(i) - delayed) / 2.;
+        });
+        self.index = 0;
+        self.last = 0.;
+    }
+}
+
+/// Waveshaper oscillator.
+/// Drives an existing oscillator into a transfer curve, `mix` is the ratio of the shaped signal to the dry one.
+/// With `oversampling` above 1 the oscillator is evaluated that many times per sample and averaged,
+/// which keeps high drive from aliasing. Stateful oscillators like `Pluck` don't benefit from it.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Waveshaper {
+    pub curve: Curve,
+    pub drive: f32,
+    #[serde(default = "shape::one")]
+    pub mix: f32,
+    #[serde(default = "shape::no_oversampling")]
+    pub oversampling: usize,
+    pub oscillator: Box<dyn Oscillator>,
+}
+
+/// Bitcrusher oscillator.
+/// Reduces an existing oscillator to `bits` of resolution,
+/// holding every value for `downsample` samples.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Bitcrusher {
+    pub bits: u32,
+    #[serde(default = "shape::no_downsampling")]
+    pub downsample: usize,
+    pub oscillator: Box<dyn Oscillator>,
+}
+
+/// Maps an integer to a pseudo-random number in `[0, 1)` (splitmix64).
+fn random(i: u64) -> f64 {
+    let mut z = i.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl Unison {
+    /// Returns the position of a specific copy, from -1 to 1.
+    fn position(&self, i: usize) -> f64 {
+        if self.voices < 2 {
+            return 0.;
+        }
+        2. * i as f64 / (self.voices - 1) as f64 - 1.
+    }
+
+    /// Returns the detune of a specific copy in cents.
+    fn cents(&self, i: usize) -> f64 {
+        let position = self.position(i);
+        position.signum() * position.abs().powf(self.curve) * self.detune / 2.
+    }
+}
+
+impl Pitch {
+    fn frequency(&self, frequency: Hertz<f64>) -> Hertz<f64> {
+        match self.fixed {
+            Some(fixed) => fixed.hz(),
+            None => {
+                let semitones = 12. * self.octaves + self.semitones + self.cents / 100.;
+                (*frequency * 2f64.powf(semitones / 12.) * self.ratio).hz()
+            }
+        }
+    }
+}
+
 #[typetag::serde]
 impl Oscillator for Sine {
-    fn value(&self, frequency: Hertz<f64>, time: f64) -> f32 {
+    fn value(&self, frequency: Hertz<f64>, time: f64, _state: &mut State) -> f32 {
         (frequency.angular_velocity() * time).sin() as f32
     }
 }
 
 #[typetag::serde]
 impl Oscillator for Square {
-    fn value(&self, frequency: Hertz<f64>, time: f64) -> f32 {
-        Sine.value(frequency, time).signum()
+    fn value(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> f32 {
+        Sine.value(frequency, time, state).signum()
     }
 }
 
 #[typetag::serde]
 impl Oscillator for Triangle {
-    fn value(&self, frequency: Hertz<f64>, time: f64) -> f32 {
-        Sine.value(frequency, time).asin()
+    fn value(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> f32 {
+        Sine.value(frequency, time, state).asin()
     }
 }
 
 #[typetag::serde]
 impl Oscillator for Sawtooth {
-    fn value(&self, frequency: Hertz<f64>, time: f64) -> f32 {
+    fn value(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> f32 {
         (2. / ::std::f32::consts::PI)
             * (1..(1 + self.num_sinewaves))
                 .into_iter()
-                .map(|i| Sine.value(frequency, i as f64 * time) / -(i as f32))
+                .map(|i| Sine.value(frequency, i as f64 * time, state) / -(i as f32))
                 .sum::<f32>()
     }
 }
 
 #[typetag::serde]
 impl Oscillator for SawtoothFast {
-    fn value(&self, frequency: Hertz<f64>, time: f64) -> f32 {
+    fn value(&self, frequency: Hertz<f64>, time: f64, _state: &mut State) -> f32 {
         ((2. / ::std::f64::consts::PI)
             * (*frequency * ::std::f64::consts::PI * (time % (1. / *frequency))
                 - (::std::f64::consts::PI / 2.))) as f32
     }
 }
 
+#[typetag::serde]
+impl Oscillator for Additive {
+    fn value(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> f32 {
+        let sample_rate = state.sample_rate;
+        let nyquist = sample_rate / 2.;
+        // counted in samples, `time` isn't the time since the key was pressed inside a hard sync
+        let (samples, fresh) = state.slot::<u64>();
+        if fresh {
+            *samples = 0;
+        }
+        let pressed_for_time = *samples as f64 / sample_rate;
+        *samples += 1;
+        let mut value = 0.;
+        self.partials.for_each(|partial| {
+            if *frequency * partial.ratio >= nyquist {
+                return;
+            }
+            let decay = if partial.decay > 0. {
+                (-pressed_for_time / partial.decay).exp()
+            } else {
+                1.
+            };
+            value += partial.amplitude
+                * decay as f32
+                * (frequency.angular_velocity() * partial.ratio * time + partial.phase).sin()
+                    as f32;
+        });
+        value
+    }
+}
+
 #[typetag::serde]
 impl Oscillator for Amplitude {
-    fn value(&self, frequency: Hertz<f64>, time: f64) -> f32 {
-        self.amplitude * self.oscillator.value(frequency, time)
+    fn value(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> f32 {
+        self.amplitude * self.oscillator.value(frequency, time, state)
+    }
+
+    fn stereo(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> Frame {
+        frame::scale(
+            self.oscillator.stereo(frequency, time, state),
+            self.amplitude,
+        )
+    }
+}
+
+#[typetag::serde]
+impl Oscillator for Unison {
+    fn value(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> f32 {
+        frame::mono(self.stereo(frequency, time, state))
+    }
+
+    fn stereo(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> Frame {
+        if self.voices == 0 {
+            return [0., 0.];
+        }
+        let sum = (0..self.voices).fold([0., 0.], |sum, i| {
+            let frequency = (*frequency * 2f64.powf(self.cents(i) / 1200.)).hz();
+            let offset = if self.random_phase {
+                random(i as u64) / *frequency
+            } else {
+                0.
+            };
+            let value = self.oscillator.stereo(frequency, time + offset, state);
+            frame::add(
+                sum,
+                frame::balance(value, self.position(i) as f32 * self.spread),
+            )
+        });
+        frame::scale(sum, 1. / self.voices as f32)
+    }
+}
+
+#[typetag::serde]
+impl Oscillator for Pitch {
+    fn value(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> f32 {
+        self.oscillator
+            .value(self.frequency(frequency), time, state)
+    }
+
+    fn stereo(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> Frame {
+        self.oscillator
+            .stereo(self.frequency(frequency), time, state)
+    }
+}
+
+#[typetag::serde]
+impl Oscillator for Pan {
+    fn value(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> f32 {
+        frame::mono(self.stereo(frequency, time, state))
+    }
+
+    fn stereo(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> Frame {
+        let modulation = match &self.modulator {
+            Some(modulator) => modulator.value(frequency, time, state),
+            None => 0.,
+        };
+        frame::balance(
+            self.oscillator.stereo(frequency, time, state),
+            self.pan + modulation,
+        )
+    }
+}
+
+#[typetag::serde]
+impl Oscillator for RingMod {
+    fn value(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> f32 {
+        self.carrier.value(frequency, time, state) * self.modulator.value(frequency, time, state)
+    }
+
+    fn stereo(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> Frame {
+        let carrier = self.carrier.stereo(frequency, time, state);
+        frame::scale(carrier, self.modulator.value(frequency, time, state))
+    }
+}
+
+impl AmplitudeMod {
+    fn gain(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> f32 {
+        let modulator = self.modulator.value(frequency, time, state);
+        1. - self.depth * (1. - modulator) / 2.
+    }
+}
+
+#[typetag::serde]
+impl Oscillator for AmplitudeMod {
+    fn value(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> f32 {
+        let gain = self.gain(frequency, time, state);
+        self.carrier.value(frequency, time, state) * gain
+    }
+
+    fn stereo(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> Frame {
+        let gain = self.gain(frequency, time, state);
+        frame::scale(self.carrier.stereo(frequency, time, state), gain)
+    }
+}
+
+#[typetag::serde(name = "Sync")]
+impl Oscillator for HardSync {
+    fn value(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> f32 {
+        self.oscillator.value(
+            (*frequency * self.ratio).hz(),
+            time % (1. / *frequency),
+            state,
+        )
+    }
+
+    fn stereo(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> Frame {
+        self.oscillator.stereo(
+            (*frequency * self.ratio).hz(),
+            time % (1. / *frequency),
+            state,
+        )
+    }
+}
+
+#[typetag::serde]
+impl Oscillator for Pluck {
+    fn value(&self, frequency: Hertz<f64>, _time: f64, state: &mut State) -> f32 {
+        let sample_rate = state.sample_rate;
+        let seed = state.time_pressed.to_bits();
+        let damping = if state.released {
+            self.release_damping
+        } else {
+            self.damping
+        };
+        let (string, fresh) = state.slot::<PluckString>();
+        if fresh {
+            string.pluck(self, frequency, sample_rate, seed);
+        }
+
+        let out = string.buffer[string.index];
+        let filtered =
+            out * (1. + self.brightness) / 2. + string.last * (1. - self.brightness) / 2.;
+        let delayed = string.allpass * (filtered - string.allpass_out) + string.allpass_in;
+        string.allpass_in = filtered;
+        string.allpass_out = delayed;
+        string.buffer[string.index] = delayed * (1. - damping.clamp(0., 1.));
+        string.index = (string.index + 1) % string.buffer.len();
+        string.last = out;
+        out
+    }
+}
+
+#[typetag::serde]
+impl Oscillator for Waveshaper {
+    fn value(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> f32 {
+        frame::mono(self.stereo(frequency, time, state))
+    }
+
+    fn stereo(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> Frame {
+        let oversampling = self.oversampling.max(1);
+        let step = 1. / (state.sample_rate * oversampling as f64);
+        let sum = (0..oversampling).fold([0., 0.], |sum, i| {
+            let value = self
+                .oscillator
+                .stereo(frequency, time - i as f64 * step, state)
+                .map(|value| shape::shape(&self.curve, self.drive, self.mix, value));
+            frame::add(sum, value)
+        });
+        frame::scale(sum, 1. / oversampling as f32)
+    }
+}
+
+impl Bitcrusher {
+    fn held_time(&self, time: f64, sample_rate: f64) -> f64 {
+        let hold = self.downsample.max(1) as f64 / sample_rate;
+        (time / hold).floor() * hold
+    }
+}
+
+#[typetag::serde]
+impl Oscillator for Bitcrusher {
+    fn value(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> f32 {
+        let time = self.held_time(time, state.sample_rate);
+        shape::crush(self.oscillator.value(frequency, time, state), self.bits)
+    }
+
+    fn stereo(&self, frequency: Hertz<f64>, time: f64, state: &mut State) -> Frame {
+        let time = self.held_time(time, state.sample_rate);
+        self.oscillator
+            .stereo(frequency, time, state)
+            .map(|value| shape::crush(value, self.bits))
+    }
+}
+
+#[typetag::serde]
+impl Oscillator for Input {
+    fn value(&self, _frequency: Hertz<f64>, _time: f64, state: &mut State) -> f32 {
+        frame::mono(state.input)
+    }
+
+    fn stereo(&self, _frequency: Hertz<f64>, _time: f64, state: &mut State) -> Frame {
+        state.input
+    }
+}
+
+#[typetag::serde]
+impl Oscillator for Follower {
+    fn value(&self, _frequency: Hertz<f64>, _time: f64, state: &mut State) -> f32 {
+        let level = state.input[0].abs().max(state.input[1].abs());
+        let coefficient = |time: f64| (1. - (-1. / (time * state.sample_rate)).exp()) as f32;
+        let attack = coefficient(self.attack);
+        let release = coefficient(self.release);
+        let (envelope, fresh) = state.slot::<f32>();
+        if fresh {
+            *envelope = 0.;
+        }
+        let coefficient = if level > *envelope { attack } else { release };
+        *envelope += (level - *envelope) * coefficient;
+        *envelope
     }
 }
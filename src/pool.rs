@@ -0,0 +1,234 @@
+use crate::{engine::BLOCK_SIZE, frame::Frame, instrument::Instrument, key::Key, state::State};
+use std::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// Scheduling priority of the worker threads, below JACK's own real-time threads.
+const WORKER_PRIORITY: i32 = 70;
+
+const IDLE: u8 = 0;
+/// Handed a job the worker hasn't picked up yet, it can still be taken back.
+const READY: u8 = 1;
+const RUNNING: u8 = 2;
+const DONE: u8 = 3;
+const STOP: u8 = 4;
+
+/// The voices to render for one block.
+/// Threads claim voices one at a time through a shared index, so no two threads touch the same
+/// state and nobody waits for a voice that nobody started.
+#[derive(Clone, Copy)]
+pub struct Job<'a> {
+    instrument: *const Instrument,
+    keys: *const [Key; 256],
+    states: *mut State,
+    active: *const usize,
+    active_len: usize,
+    time: f64,
+    inputs: *const Frame,
+    len: usize,
+    _borrow: PhantomData<&'a mut [State]>,
+}
+
+impl<'a> Job<'a> {
+    /// `active` holds the numbers of the keys to render, `inputs` is as long as the block.
+    pub fn new(
+        instrument: &'a Instrument,
+        keys: &'a [Key; 256],
+        states: &'a mut [State],
+        active: &'a [usize],
+        time: f64,
+        inputs: &'a [Frame],
+    ) -> Self {
+        assert!(states.len() == keys.len() && inputs.len() <= BLOCK_SIZE);
+        Self {
+            instrument,
+            keys,
+            states: states.as_mut_ptr(),
+            active: active.as_ptr(),
+            active_len: active.len(),
+            time,
+            inputs: inputs.as_ptr(),
+            len: inputs.len(),
+            _borrow: PhantomData,
+        }
+    }
+
+    /// Renders active keys until `next` runs past the last one.
+    /// Safety: concurrent calls have to claim through the same `next`, which must have started
+    /// at zero for this job.
+    unsafe fn render(&self, next: &AtomicUsize, out: &mut [Frame]) {
+        let instrument = &*self.instrument;
+        let keys = &*self.keys;
+        let active = std::slice::from_raw_parts(self.active, self.active_len);
+        let inputs = std::slice::from_raw_parts(self.inputs, self.len);
+        while let Some(&key_number) = active.get(next.fetch_add(1, Ordering::Relaxed)) {
+            instrument.render_voice(
+                key_number,
+                &keys[key_number],
+                &mut *self.states.add(key_number),
+                self.time,
+                inputs,
+                &mut out[..self.len],
+            );
+        }
+    }
+}
+
+struct Shared {
+    state: AtomicU8,
+    job: UnsafeCell<Option<Job<'static>>>,
+    block: UnsafeCell<[Frame; BLOCK_SIZE]>,
+}
+
+// the job and the block are only touched by whoever `state` hands them to
+unsafe impl Send for Shared {}
+unsafe impl Sync for Shared {}
+
+struct Worker {
+    shared: Arc<Shared>,
+    thread: JoinHandle<()>,
+}
+
+/// Threads rendering voices alongside the audio thread.
+/// Workers sleep until they get a job and help with whatever voices are left when they wake up.
+/// The audio thread renders voices too, takes back jobs no worker picked up in time, and only
+/// waits for workers to finish the voice they're on, so a block never waits on a lock or on a
+/// worker that didn't get scheduled.
+pub struct Pool {
+    workers: Vec<Worker>,
+    /// Index of the next active key to render.
+    next: Arc<AtomicUsize>,
+}
+
+impl Pool {
+    /// Spawns `threads` workers, with none every voice is rendered on the audio thread.
+    /// Workers without real-time priority would be starved by the audio thread, so failing to
+    /// set it is fatal.
+    pub fn new(threads: usize) -> Self {
+        let next = Arc::new(AtomicUsize::new(0));
+        let (started_sender, started) = mpsc::channel();
+        let workers = (0..threads)
+            .map(|i| {
+                let shared = Arc::new(Shared {
+                    state: AtomicU8::new(IDLE),
+                    job: UnsafeCell::new(None),
+                    block: UnsafeCell::new([[0., 0.]; BLOCK_SIZE]),
+                });
+                let thread = thread::Builder::new()
+                    .name(format!("rsynth-voices-{i}"))
+                    .spawn({
+                        let shared = Arc::clone(&shared);
+                        let next = Arc::clone(&next);
+                        let started = started_sender.clone();
+                        move || {
+                            let priority = set_real_time_priority();
+                            let failed = priority.is_err();
+                            started
+                                .send(priority)
+                                .expect("failed to report worker thread priority!");
+                            if !failed {
+                                work(shared, next);
+                            }
+                        }
+                    })
+                    .expect("failed to spawn worker thread!");
+                Worker { shared, thread }
+            })
+            .collect::<Vec<_>>();
+        for _ in 0..threads {
+            if let Err(err) = started.recv().expect("failed to start worker thread!") {
+                panic!("failed to give the worker threads real-time priority, allow real-time scheduling or drop --threads!\n{err:?}");
+            }
+        }
+        Self { workers, next }
+    }
+
+    /// Renders every voice of `job` and adds them onto `out`.
+    pub fn render(&mut self, job: Job, out: &mut [Frame]) {
+        // the job's borrows outlive it, because this waits for every worker to finish
+        let job = unsafe { std::mem::transmute::<Job, Job<'static>>(job) };
+        self.next.store(0, Ordering::Relaxed);
+        for worker in &self.workers {
+            unsafe { *worker.shared.job.get() = Some(job) };
+            worker.shared.state.store(READY, Ordering::Release);
+            worker.thread.thread().unpark();
+        }
+
+        unsafe { job.render(&self.next, out) };
+
+        for worker in &self.workers {
+            // a worker that hasn't woken up yet didn't claim anything and never will
+            let taken_back = worker
+                .shared
+                .state
+                .compare_exchange(READY, IDLE, Ordering::Acquire, Ordering::Acquire)
+                .is_ok();
+            if !taken_back {
+                // every voice is claimed, so this only waits for the ones being rendered
+                while worker.shared.state.load(Ordering::Acquire) != DONE {
+                    std::hint::spin_loop();
+                }
+                let block = unsafe { &*worker.shared.block.get() };
+                out.iter_mut()
+                    .zip(block)
+                    .for_each(|(out, frame)| *out = crate::frame::add(*out, *frame));
+            }
+            unsafe { *worker.shared.job.get() = None };
+            worker.shared.state.store(IDLE, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        for worker in self.workers.drain(..) {
+            worker.shared.state.store(STOP, Ordering::Release);
+            worker.thread.thread().unpark();
+            worker.thread.join().expect("failed to join worker thread!");
+        }
+    }
+}
+
+fn work(shared: Arc<Shared>, next: Arc<AtomicUsize>) {
+    loop {
+        match shared.state.load(Ordering::Acquire) {
+            READY => {
+                // the audio thread may have taken the job back in the meantime
+                if shared
+                    .state
+                    .compare_exchange(READY, RUNNING, Ordering::Acquire, Ordering::Relaxed)
+                    .is_err()
+                {
+                    continue;
+                }
+                unsafe {
+                    let job = (*shared.job.get()).expect("worker started without a job!");
+                    let block = &mut *shared.block.get();
+                    block[..job.len].fill([0., 0.]);
+                    job.render(&next, block);
+                }
+                shared.state.store(DONE, Ordering::Release);
+            }
+            STOP => return,
+            _ => thread::park(),
+        }
+    }
+}
+
+fn set_real_time_priority() -> std::io::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: WORKER_PRIORITY,
+    };
+    let result =
+        unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+    match result {
+        0 => Ok(()),
+        error => Err(std::io::Error::from_raw_os_error(error)),
+    }
+}
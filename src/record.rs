@@ -0,0 +1,494 @@
+use crate::{engine::Status, frame::Frame, midi::ShortMessage, smf::Smf};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// Recorded messages that can wait for the writer before new ones get dropped.
+const QUEUE_LENGTH: usize = 4096;
+
+/// Recorded frames that can wait for the writer before new ones get dropped, about ten seconds
+/// at 48 kHz.
+const RING_LENGTH: usize = 1 << 19;
+
+/// How often the audio writer empties the ring.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum AudioFormat {
+    /// 32 bit floating point samples
+    Float32,
+    /// 24 bit integer samples
+    Int24,
+}
+
+/// What to record and where to.
+#[derive(clap::Args, Debug)]
+#[command(about = None, long_about = None)]
+#[group(id = "record_options")]
+pub struct Options {
+    /// Start recording MIDI right away, toggle it with `m` in the UI
+    #[arg(long)]
+    pub record_midi: bool,
+    /// File MIDI is recorded into, takes after the first get a number appended
+    #[arg(long, default_value = "rsynth.mid")]
+    pub midi_output: PathBuf,
+    /// Start recording the audio output right away, toggle it with `a` in the UI
+    #[arg(long)]
+    pub record_audio: bool,
+    /// WAV file the audio output is recorded into, takes after the first get a number appended
+    #[arg(long, default_value = "rsynth.wav")]
+    pub audio_output: PathBuf,
+    /// Sample format of recorded audio
+    #[arg(long, value_enum, default_value_t = AudioFormat::Float32)]
+    pub audio_format: AudioFormat,
+}
+
+/// Returns `path` if nothing is there yet, and otherwise the first free `<stem>-<n>.<ext>`.
+fn free_path(path: &Path) -> PathBuf {
+    if !path.exists() {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (2..)
+        .map(|n| path.with_file_name(format!("{stem}-{n}{extension}")))
+        .find(|path| !path.exists())
+        .expect("failed to find a free file name!")
+}
+
+/// What the engine hands the MIDI writer, with the engine time it happened at.
+enum Recorded {
+    Start,
+    Message(ShortMessage),
+    /// The take is over, `dropped` messages of it didn't fit into the queue.
+    Stop {
+        dropped: u64,
+    },
+}
+
+/// A MIDI take being recorded, keeping track of the keys held in it.
+struct Take {
+    start: f64,
+    smf: Smf,
+    /// Whether a key is held, by channel and key number.
+    held: [[bool; 128]; 16],
+}
+
+impl Take {
+    fn new(start: f64) -> Self {
+        Self {
+            start,
+            smf: Smf { messages: vec![] },
+            held: [[false; 128]; 16],
+        }
+    }
+
+    fn push(&mut self, time: f64, message: ShortMessage) {
+        if let &[status, key_number, velocity] = message.as_bytes() {
+            let held = &mut self.held[(status & 0x0F) as usize][(key_number & 0x7F) as usize];
+            match status >> 4 {
+                0x8 => *held = false,
+                0x9 => *held = velocity != 0,
+                _ => (),
+            }
+        }
+        self.smf.messages.push((time - self.start, message));
+    }
+
+    /// Engine time of the last message, or of the start if there's none.
+    fn last_time(&self) -> f64 {
+        self.start + self.smf.messages.last().map_or(0., |(time, _)| *time)
+    }
+
+    /// Ends the take at `time`, releasing the keys still held so no note of the file hangs.
+    fn end(mut self, time: f64) -> Smf {
+        let time = time.max(self.last_time()) - self.start;
+        for (channel, keys) in self.held.iter().enumerate() {
+            for (key_number, _) in keys.iter().enumerate().filter(|(_, held)| **held) {
+                let bytes = [0x80 | channel as u8, key_number as u8, 0];
+                self.smf.messages.push((time, ShortMessage::new(&bytes)));
+            }
+        }
+        self.smf
+    }
+}
+
+/// The engine's end of MIDI recording.
+/// It follows `Status::record_midi` at the start of every period and passes on the channel
+/// messages the engine plays while it's set, never waiting for the writer.
+/// Messages that don't fit into the queue are counted, the writer logs them with the take.
+pub struct MidiCapture {
+    sender: SyncSender<(f64, Recorded)>,
+    recording: bool,
+    dropped: u64,
+}
+
+impl MidiCapture {
+    pub fn update(&mut self, status: &Status, time: f64) {
+        let recording = status.record_midi.load(Ordering::Relaxed);
+        if recording != self.recording {
+            let recorded = if recording {
+                Recorded::Start
+            } else {
+                Recorded::Stop {
+                    dropped: self.dropped,
+                }
+            };
+            // start and stop can't get lost, or takes would run into each other, so they're
+            // retried every period until they fit
+            if let Err(TrySendError::Full(_)) = self.sender.try_send((time, recorded)) {
+                return;
+            }
+            self.recording = recording;
+            self.dropped = 0;
+        }
+    }
+
+    pub fn message(&mut self, time: f64, bytes: &[u8]) {
+        // system messages have no place in a file, and real-time ones would only bloat it
+        if self.recording && bytes.len() <= 3 && (0x80..0xF0).contains(&bytes[0]) {
+            let recorded = Recorded::Message(ShortMessage::new(bytes));
+            if let Err(TrySendError::Full(_)) = self.sender.try_send((time, recorded)) {
+                self.dropped += 1;
+            }
+        }
+    }
+}
+
+/// A thread writing recorded takes into files.
+pub struct Writer {
+    finish: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Writer {
+    fn spawn(name: &str, f: impl FnOnce(&AtomicBool) + Send + 'static) -> Self {
+        let finish = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let finish = Arc::clone(&finish);
+            thread::Builder::new()
+                .name(name.to_string())
+                .spawn(move || f(&finish))
+                .expect("failed to spawn writer thread!")
+        };
+        Self { finish, thread }
+    }
+
+    /// Writes the take that's still being recorded, if any, and waits for the thread.
+    pub fn finish(self) {
+        self.finish.store(true, Ordering::Release);
+        self.thread.join().expect("failed to join writer thread!");
+    }
+}
+
+/// Starts the MIDI writer, recording right away if `options` say so.
+pub fn midi(options: &Options, status: &Status) -> (MidiCapture, Writer) {
+    status
+        .record_midi
+        .store(options.record_midi, Ordering::Relaxed);
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+    let path = options.midi_output.clone();
+    (
+        MidiCapture {
+            sender,
+            recording: false,
+            dropped: 0,
+        },
+        Writer::spawn("rsynth-midi-writer", move |finish| {
+            write_midi_takes(&path, &receiver, finish)
+        }),
+    )
+}
+
+fn write_midi_takes(path: &Path, receiver: &Receiver<(f64, Recorded)>, finish: &AtomicBool) {
+    let mut take: Option<Take> = None;
+    let save = |smf: Smf, dropped: u64| {
+        let path = free_path(path);
+        match smf.write(&path) {
+            Ok(()) => tracing::info!("recorded midi into {}", path.display()),
+            Err(err) => tracing::warn!("failed to write {}: {err}", path.display()),
+        }
+        if dropped > 0 {
+            tracing::warn!("dropped {dropped} messages recording {}", path.display());
+        }
+    };
+    loop {
+        match receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok((time, Recorded::Start)) => {
+                take = Some(Take::new(time));
+            }
+            Ok((time, Recorded::Message(message))) => {
+                if let Some(take) = &mut take {
+                    take.push(time, message);
+                }
+            }
+            Ok((time, Recorded::Stop { dropped })) => {
+                if let Some(take) = take.take() {
+                    save(take.end(time), dropped);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) if !finish.load(Ordering::Acquire) => (),
+            Err(_) => break,
+        }
+    }
+    // the engine doesn't tell when it stopped, so held keys are released with the last message
+    if let Some(take) = take {
+        let time = take.last_time();
+        save(take.end(time), 0);
+    }
+}
+
+/// What the engine tells the audio writer besides the frames themselves.
+enum Control {
+    Start {
+        channels: u16,
+        sample_rate: u32,
+    },
+    /// The take is over once `frames` frames of it are written.
+    Stop {
+        frames: u64,
+        dropped: u64,
+    },
+}
+
+/// The engine's end of audio recording.
+/// It follows `Status::record_audio` every period, copying the output into a ring the writer
+/// empties. Neither side ever waits for the other, frames that don't fit are dropped.
+pub struct AudioCapture {
+    frames: Producer<Frame>,
+    control: SyncSender<Control>,
+    recording: bool,
+    /// Frames pushed and dropped in the current take.
+    pushed: u64,
+    dropped: u64,
+}
+
+impl AudioCapture {
+    pub fn process(
+        &mut self,
+        status: &Status,
+        sample_rate: f64,
+        left: &[f32],
+        right: Option<&[f32]>,
+    ) {
+        let recording = status.record_audio.load(Ordering::Relaxed);
+        // a new take waits until the writer is done with the last one, so they can't mix
+        let idle = self.frames.slots() == self.frames.buffer().capacity();
+        if recording != self.recording && (idle || !recording) {
+            let control = if recording {
+                Control::Start {
+                    channels: if right.is_some() { 2 } else { 1 },
+                    sample_rate: sample_rate as u32,
+                }
+            } else {
+                Control::Stop {
+                    frames: self.pushed,
+                    dropped: self.dropped,
+                }
+            };
+            // start and stop can't get lost, or the writer would mix up takes
+            if self.control.try_send(control).is_err() {
+                return;
+            }
+            self.recording = recording;
+            self.pushed = 0;
+            self.dropped = 0;
+        }
+        if !self.recording {
+            return;
+        }
+
+        let len = left.len().min(self.frames.slots());
+        let Ok(chunk) = self.frames.write_chunk_uninit(len) else {
+            return;
+        };
+        let right = right.unwrap_or(left);
+        chunk.fill_from_iter(left.iter().zip(right).map(|(left, right)| [*left, *right]));
+        self.pushed += len as u64;
+        self.dropped += (left.len() - len) as u64;
+    }
+}
+
+/// Starts the audio writer, recording right away if `options` say so.
+pub fn audio(options: &Options, status: &Status) -> (AudioCapture, Writer) {
+    status
+        .record_audio
+        .store(options.record_audio, Ordering::Relaxed);
+    let (producer, consumer) = RingBuffer::new(RING_LENGTH);
+    let (control, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+    let path = options.audio_output.clone();
+    let format = options.audio_format;
+    (
+        AudioCapture {
+            frames: producer,
+            control,
+            recording: false,
+            pushed: 0,
+            dropped: 0,
+        },
+        Writer::spawn("rsynth-audio-writer", move |finish| {
+            write_audio_takes(&path, format, consumer, &receiver, finish)
+        }),
+    )
+}
+
+/// A WAV file being recorded. Frames are still taken off the ring when it couldn't be created,
+/// so the takes after it stay in line.
+struct AudioTake {
+    path: PathBuf,
+    writer: Option<WavWriter<BufWriter<File>>>,
+    channels: u16,
+    format: AudioFormat,
+    written: u64,
+}
+
+impl AudioTake {
+    fn create(path: &Path, format: AudioFormat, channels: u16, sample_rate: u32) -> Self {
+        let path = free_path(path);
+        let spec = match format {
+            AudioFormat::Float32 => WavSpec {
+                channels,
+                sample_rate,
+                bits_per_sample: 32,
+                sample_format: SampleFormat::Float,
+            },
+            AudioFormat::Int24 => WavSpec {
+                channels,
+                sample_rate,
+                bits_per_sample: 24,
+                sample_format: SampleFormat::Int,
+            },
+        };
+        let writer = WavWriter::create(&path, spec)
+            .map_err(|err| tracing::warn!("failed to create {}: {err}", path.display()))
+            .ok();
+        Self {
+            path,
+            writer,
+            channels,
+            format,
+            written: 0,
+        }
+    }
+
+    /// Writes the frames waiting in the ring, but no more than `limit` frames in total.
+    fn drain(&mut self, frames: &mut Consumer<Frame>, limit: u64) {
+        let len = frames.slots().min((limit - self.written) as usize);
+        let Ok(chunk) = frames.read_chunk(len) else {
+            return;
+        };
+        let (first, second) = chunk.as_slices();
+        for frame in first.iter().chain(second) {
+            for sample in &frame[..self.channels as usize] {
+                self.write(*sample);
+            }
+        }
+        chunk.commit_all();
+        self.written += len as u64;
+    }
+
+    fn write(&mut self, sample: f32) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        let result = match self.format {
+            AudioFormat::Float32 => writer.write_sample(sample),
+            AudioFormat::Int24 => writer.write_sample((sample.clamp(-1., 1.) * 8_388_607.) as i32),
+        };
+        if let Err(err) = result {
+            tracing::warn!("failed to write {}: {err}", self.path.display());
+            self.writer = None;
+        }
+    }
+
+    fn finish(self) {
+        if let Some(writer) = self.writer {
+            match writer.finalize() {
+                Ok(()) => tracing::info!("recorded audio into {}", self.path.display()),
+                Err(err) => tracing::warn!("failed to finalize {}: {err}", self.path.display()),
+            }
+        }
+    }
+}
+
+fn write_audio_takes(
+    path: &Path,
+    format: AudioFormat,
+    mut frames: Consumer<Frame>,
+    receiver: &Receiver<Control>,
+    finish: &AtomicBool,
+) {
+    let mut take: Option<AudioTake> = None;
+    loop {
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(Control::Start {
+                channels,
+                sample_rate,
+            }) => {
+                take = Some(AudioTake::create(path, format, channels, sample_rate));
+            }
+            Ok(Control::Stop {
+                frames: len,
+                dropped,
+            }) => {
+                if let Some(mut take) = take.take() {
+                    // every frame of the take was pushed before the engine sent this
+                    take.drain(&mut frames, len);
+                    if dropped > 0 {
+                        tracing::warn!(
+                            "dropped {dropped} frames recording {}",
+                            take.path.display()
+                        );
+                    }
+                    take.finish();
+                }
+            }
+            Err(RecvTimeoutError::Timeout) if !finish.load(Ordering::Acquire) => {
+                if let Some(take) = &mut take {
+                    take.drain(&mut frames, u64::MAX);
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    if let Some(mut take) = take {
+        take.drain(&mut frames, u64::MAX);
+        take.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ending_a_take_releases_held_keys() {
+        let mut take = Take::new(10.);
+        take.push(10.5, ShortMessage::new(&[0x90, 60, 100]));
+        take.push(11., ShortMessage::new(&[0x91, 62, 100]));
+        take.push(11.5, ShortMessage::new(&[0x90, 64, 100]));
+        take.push(12., ShortMessage::new(&[0x90, 60, 0]));
+        take.push(12.5, ShortMessage::new(&[0x80, 64, 0]));
+        let smf = take.end(13.);
+
+        let released = smf.messages[5..]
+            .iter()
+            .map(|(time, message)| (*time, message.as_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(released, [(3., vec![0x81, 62, 0])]);
+    }
+}
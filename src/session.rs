@@ -0,0 +1,77 @@
+use crate::master::Master;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Instruments played at once, each on its own MIDI channel or on all of them.
+/// `master` is applied to the sum of all parts, on top of every instrument's own. Sessions of
+/// more than one part get the default master section if they don't set one, so the parts can't
+/// add up to more than full scale.
+#[derive(Deserialize, Serialize)]
+pub struct Session {
+    pub parts: Vec<Part>,
+    #[serde(default)]
+    pub master: Option<Master>,
+}
+
+/// An instrument together with the channel it listens to, from 1 to 16, or every channel if
+/// `channel` is missing. `instrument` is relative to the session file.
+/// `volume` and `pan` are applied on top of the instrument's own.
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct Part {
+    pub channel: Option<u8>,
+    pub instrument: PathBuf,
+    pub volume: f32,
+    pub pan: f32,
+}
+
+impl Default for Part {
+    fn default() -> Self {
+        Self {
+            channel: None,
+            instrument: PathBuf::new(),
+            volume: 1.,
+            pan: 0.,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionReadError {
+    IoError(io::Error),
+    Deserialize(serde_yaml::Error),
+    /// A part's channel isn't between 1 and 16.
+    InvalidChannel(u8),
+}
+
+impl Session {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, SessionReadError> {
+        let s = fs::read_to_string(&path).map_err(SessionReadError::IoError)?;
+        let mut session: Self = serde_yaml::from_str(&s).map_err(SessionReadError::Deserialize)?;
+        let directory = path.as_ref().parent().unwrap_or(Path::new(""));
+        for part in &mut session.parts {
+            if let Some(channel) = part.channel.filter(|channel| !(1..=16).contains(channel)) {
+                return Err(SessionReadError::InvalidChannel(channel));
+            }
+            part.instrument = directory.join(&part.instrument);
+        }
+        if session.parts.len() > 1 && session.master.is_none() {
+            session.master = Some(Master::default());
+        }
+        Ok(session)
+    }
+
+    /// A session of a single instrument playing on every channel.
+    pub fn single(instrument: PathBuf) -> Self {
+        Self {
+            parts: vec![Part {
+                instrument,
+                ..Default::default()
+            }],
+            master: None,
+        }
+    }
+}
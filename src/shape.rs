@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+
+/// Transfer curve of a waveshaper.
+/// `Table` maps inputs from -1 to 1 evenly onto its points, interpolating between them.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Curve {
+    Tanh,
+    HardClip,
+    Foldback,
+    Table(Vec<f32>),
+}
+
+impl Curve {
+    pub fn apply(&self, x: f32) -> f32 {
+        match self {
+            Curve::Tanh => x.tanh(),
+            Curve::HardClip => x.clamp(-1., 1.),
+            Curve::Foldback => {
+                // reflect everything outside of [-1, 1] back into it
+                let folded = (x + 1.).rem_euclid(4.);
+                if folded < 2. {
+                    folded - 1.
+                } else {
+                    3. - folded
+                }
+            }
+            Curve::Table(points) => match points.len() {
+                0 => x,
+                1 => points[0],
+                len => {
+                    let position = (x.clamp(-1., 1.) + 1.) / 2. * (len - 1) as f32;
+                    let index = (position as usize).min(len - 2);
+                    let fraction = position - index as f32;
+                    points[index] + (points[index + 1] - points[index]) * fraction
+                }
+            },
+        }
+    }
+}
+
+/// Drives a sample into a curve and mixes the result with the dry sample.
+pub fn shape(curve: &Curve, drive: f32, mix: f32, x: f32) -> f32 {
+    x * (1. - mix) + curve.apply(x * drive) * mix
+}
+
+/// Reduces a sample to a bit depth, clamped between 1 and 24 bits.
+pub fn crush(x: f32, bits: u32) -> f32 {
+    let levels = 2f32.powi(bits.clamp(1, 24) as i32 - 1);
+    (x * levels).round() / levels
+}
+
+/// Default of gains, mixes and ratios, for both `f32` and `f64` fields.
+pub fn one<T: From<u8>>() -> T {
+    T::from(1)
+}
+
+pub fn no_oversampling() -> usize {
+    1
+}
+
+pub fn no_downsampling() -> usize {
+    1
+}
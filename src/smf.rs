@@ -0,0 +1,471 @@
+use crate::{
+    event::{EventSource, Events},
+    midi::ShortMessage,
+};
+use std::{fs, io, path::Path};
+
+/// Ticks per quarter note of written files, at the default tempo a tick is about a millisecond.
+const DIVISION: u16 = 480;
+
+/// Tempo of a file until its first tempo change, 120 BPM.
+const DEFAULT_TEMPO: u32 = 500_000;
+
+#[derive(Debug)]
+pub enum SmfReadError {
+    IoError(io::Error),
+    /// The file isn't a standard MIDI file, or is cut off.
+    Invalid(&'static str),
+    /// Format 2 files hold independent patterns rather than one piece.
+    UnsupportedFormat(u16),
+}
+
+/// The messages of a standard MIDI file, with the second they're played at.
+/// Only short messages are kept, system exclusive and meta events don't affect the sound.
+pub struct Smf {
+    pub messages: Vec<(f64, ShortMessage)>,
+}
+
+/// What an event of a track holds, once its delta time is read.
+enum TrackEvent {
+    Message(ShortMessage),
+    Tempo(u32),
+    EndOfTrack,
+    Other,
+}
+
+/// Reads the chunks of a file, keeping track of where in it things went wrong.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SmfReadError> {
+        if self.bytes.len() < len {
+            return Err(SmfReadError::Invalid("unexpected end of file"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, SmfReadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SmfReadError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, SmfReadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Reads a variable-length quantity, at most four bytes of seven bits each.
+    fn variable(&mut self) -> Result<u32, SmfReadError> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte >> 7 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(SmfReadError::Invalid("variable-length quantity too long"))
+    }
+
+    /// Reads a chunk, returning its type and its data.
+    fn chunk(&mut self) -> Result<(&'a [u8], Reader<'a>), SmfReadError> {
+        let kind = self.take(4)?;
+        let len = self.u32()? as usize;
+        // some files get the length of their last chunk wrong
+        let bytes = self.take(len.min(self.bytes.len()))?;
+        Ok((kind, Reader { bytes }))
+    }
+
+    /// Reads the next event of a track, using and updating its running status.
+    fn event(&mut self, running_status: &mut Option<u8>) -> Result<TrackEvent, SmfReadError> {
+        let mut status = self.byte()?;
+        let mut first = None;
+        if status >> 7 == 0 {
+            first = Some(status);
+            status = running_status.ok_or(SmfReadError::Invalid("data byte without status"))?;
+        }
+
+        match status {
+            0xFF => {
+                let kind = self.byte()?;
+                let len = self.variable()? as usize;
+                let data = self.take(len)?;
+                Ok(match (kind, data) {
+                    (0x2F, _) => TrackEvent::EndOfTrack,
+                    (0x51, &[a, b, c]) => TrackEvent::Tempo(u32::from_be_bytes([0, a, b, c])),
+                    _ => TrackEvent::Other,
+                })
+            }
+            0xF0 | 0xF7 => {
+                let len = self.variable()? as usize;
+                self.take(len)?;
+                *running_status = None;
+                Ok(TrackEvent::Other)
+            }
+            0x80..=0xEF => {
+                *running_status = Some(status);
+                let mut bytes = [status, 0, 0];
+                let len = match status >> 4 {
+                    0xC | 0xD => 1,
+                    _ => 2,
+                };
+                for byte in &mut bytes[1..=len] {
+                    *byte = match first.take() {
+                        Some(first) => first,
+                        None => self.byte()?,
+                    };
+                }
+                Ok(TrackEvent::Message(ShortMessage::new(&bytes[..=len])))
+            }
+            _ => Err(SmfReadError::Invalid("invalid status byte")),
+        }
+    }
+}
+
+impl Smf {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, SmfReadError> {
+        let bytes = fs::read(path).map_err(SmfReadError::IoError)?;
+        Self::parse(&bytes)
+    }
+
+    /// Parses a format 0 or 1 file, merging its tracks and turning ticks into seconds.
+    pub fn parse(bytes: &[u8]) -> Result<Self, SmfReadError> {
+        let mut reader = Reader { bytes };
+        let (kind, mut header) = reader.chunk()?;
+        if kind != b"MThd" {
+            return Err(SmfReadError::Invalid("missing header"));
+        }
+        let format = header.u16()?;
+        let track_count = header.u16()?;
+        let division = header.u16()?;
+        if format > 1 {
+            return Err(SmfReadError::UnsupportedFormat(format));
+        }
+
+        // events of every track with their tick, tempo changes included since they matter for
+        // every track of a format 1 file
+        let mut events = Vec::new();
+        let mut tracks = 0;
+        while tracks < track_count && !reader.bytes.is_empty() {
+            let (kind, mut track) = reader.chunk()?;
+            // unknown chunks have to be skipped
+            if kind != b"MTrk" {
+                continue;
+            }
+            tracks += 1;
+            let mut tick = 0u64;
+            let mut running_status = None;
+            while !track.bytes.is_empty() {
+                tick += track.variable()? as u64;
+                match track.event(&mut running_status)? {
+                    TrackEvent::EndOfTrack => break,
+                    TrackEvent::Other => (),
+                    event => events.push((tick, event)),
+                }
+            }
+        }
+        // stable, so events on the same tick keep the order of their tracks
+        events.sort_by_key(|(tick, _)| *tick);
+
+        // SMPTE divisions have a fixed number of ticks per second, the tempo doesn't matter
+        let smpte = division >> 15 == 1;
+        let ticks_per_unit = if smpte {
+            // negative frames per second in the high byte, ticks per frame in the low one
+            let frames_per_second = -((division >> 8) as u8 as i8 as i16);
+            let ticks_per_frame = division & 0xFF;
+            if !matches!(frames_per_second, 24 | 25 | 29 | 30) || ticks_per_frame == 0 {
+                return Err(SmfReadError::Invalid("invalid SMPTE division"));
+            }
+            frames_per_second as f64 * ticks_per_frame as f64
+        } else {
+            division.max(1) as f64
+        };
+        let mut seconds_per_tick = if smpte {
+            1. / ticks_per_unit
+        } else {
+            DEFAULT_TEMPO as f64 / 1e6 / ticks_per_unit
+        };
+
+        let mut messages = Vec::with_capacity(events.len());
+        let mut last_tick = 0;
+        let mut time = 0.;
+        for (tick, event) in events {
+            time += (tick - last_tick) as f64 * seconds_per_tick;
+            last_tick = tick;
+            match event {
+                TrackEvent::Message(message) => messages.push((time, message)),
+                TrackEvent::Tempo(tempo) if !smpte => {
+                    seconds_per_tick = tempo as f64 / 1e6 / ticks_per_unit;
+                }
+                _ => (),
+            }
+        }
+
+        Ok(Self { messages })
+    }
+
+    /// Writes the messages into a format 0 file at 120 BPM.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let ticks_per_second = DIVISION as f64 * 1e6 / DEFAULT_TEMPO as f64;
+        let mut track = vec![0x00, 0xFF, 0x51, 0x03];
+        track.extend_from_slice(&DEFAULT_TEMPO.to_be_bytes()[1..]);
+        let mut last_tick = 0;
+        for (time, message) in &self.messages {
+            let tick = (time.max(0.) * ticks_per_second).round() as u32;
+            write_variable(&mut track, tick.saturating_sub(last_tick));
+            track.extend_from_slice(message.as_bytes());
+            last_tick = last_tick.max(tick);
+        }
+        track.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+
+        let mut bytes = Vec::with_capacity(track.len() + 22);
+        bytes.extend_from_slice(b"MThd");
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        bytes.extend_from_slice(&0u16.to_be_bytes());
+        bytes.extend_from_slice(&1u16.to_be_bytes());
+        bytes.extend_from_slice(&DIVISION.to_be_bytes());
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&track);
+        fs::write(path, bytes)
+    }
+}
+
+/// Appends `value` as a variable-length quantity, most significant seven bits first.
+fn write_variable(bytes: &mut Vec<u8>, value: u32) {
+    let value = value.min(0x0FFF_FFFF);
+    for shift in [21, 14, 7] {
+        if value >> shift != 0 {
+            bytes.push(0x80 | (value >> shift) as u8 & 0x7F);
+        }
+    }
+    bytes.push(value as u8 & 0x7F);
+}
+
+/// Plays a standard MIDI file from the start, with every message at its exact frame.
+pub struct Player {
+    messages: Vec<(f64, ShortMessage)>,
+    next: usize,
+    time: f64,
+}
+
+impl Player {
+    pub fn new(smf: Smf) -> Self {
+        Self {
+            messages: smf.messages,
+            next: 0,
+            time: 0.,
+        }
+    }
+}
+
+impl EventSource for Player {
+    fn read(&mut self, frames: usize, sample_rate: f64, events: &mut Events) {
+        let end = self.time + frames as f64 / sample_rate;
+        while let Some((time, message)) = self.messages.get(self.next) {
+            if *time >= end {
+                break;
+            }
+            let frame = ((time - self.time).max(0.) * sample_rate) as usize;
+            events.push(frame.min(frames.saturating_sub(1)), message.as_bytes());
+            self.next += 1;
+        }
+        self.time = end;
+    }
+
+    fn is_finished(&self) -> bool {
+        self.next == self.messages.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut bytes = kind.to_vec();
+        bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn header(format: u16, tracks: u16, division: u16) -> Vec<u8> {
+        let data = [format, tracks, division].map(u16::to_be_bytes).concat();
+        chunk(b"MThd", &data)
+    }
+
+    /// A file of the given tracks, each one ended with an end of track event.
+    fn file(format: u16, division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = header(format, tracks.len() as u16, division);
+        for track in tracks {
+            bytes.extend(chunk(
+                b"MTrk",
+                &[track, &[0x00, 0xFF, 0x2F, 0x00][..]].concat(),
+            ));
+        }
+        bytes
+    }
+
+    fn messages(smf: &Smf) -> Vec<(f64, Vec<u8>)> {
+        smf.messages
+            .iter()
+            .map(|(time, message)| (*time, message.as_bytes().to_vec()))
+            .collect()
+    }
+
+    fn assert_messages(smf: &Smf, expected: &[(f64, &[u8])]) {
+        let messages = messages(smf);
+        assert_eq!(messages.len(), expected.len(), "{messages:?}");
+        for ((time, bytes), (expected_time, expected_bytes)) in messages.iter().zip(expected) {
+            assert!((time - expected_time).abs() < 1e-9, "{messages:?}");
+            assert_eq!(bytes, expected_bytes);
+        }
+    }
+
+    #[test]
+    fn format_1_tempo_change() {
+        // the tempo doubles after two quarter notes, one second at the default tempo
+        let tempo_map = [
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, // 500000 microseconds per quarter note
+            0x87, 0x40, 0xFF, 0x51, 0x03, 0x03, 0xD0, 0x90, // 250000, 960 ticks later
+        ];
+        let notes = [
+            0x00, 0x90, 60, 100, // at 0
+            0x87, 0x40, 0x80, 60, 0, // 960 ticks later
+            0x83, 0x60, 0x90, 62, 100, // 480 ticks later, at the new tempo
+        ];
+        let smf = Smf::parse(&file(1, 480, &[&tempo_map, &notes])).unwrap();
+        assert_messages(
+            &smf,
+            &[
+                (0., &[0x90, 60, 100]),
+                (1., &[0x80, 60, 0]),
+                (1.25, &[0x90, 62, 100]),
+            ],
+        );
+    }
+
+    #[test]
+    fn running_status_across_note_off_velocity() {
+        let track = [
+            0x00, 0x90, 60, 100, //
+            0x83, 0x60, 60, 0, // note off as a note on without velocity
+            0x00, 62, 100, // still running
+        ];
+        let smf = Smf::parse(&file(0, 480, &[&track])).unwrap();
+        assert_messages(
+            &smf,
+            &[
+                (0., &[0x90, 60, 100]),
+                (0.5, &[0x90, 60, 0]),
+                (0.5, &[0x90, 62, 100]),
+            ],
+        );
+    }
+
+    #[test]
+    fn system_exclusive_cancels_running_status() {
+        let track = [
+            0x00, 0x90, 60, 100, //
+            0x00, 0xF0, 0x02, 0x7E, 0xF7, // system exclusive
+            0x00, 60, 0, // no status to run on
+        ];
+        assert!(matches!(
+            Smf::parse(&file(0, 480, &[&track])),
+            Err(SmfReadError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn unknown_chunks_are_skipped() {
+        let mut bytes = header(0, 1, 480);
+        bytes.extend(chunk(b"XFIH", &[0x90, 60, 100]));
+        bytes.extend(chunk(
+            b"MTrk",
+            &[0x00, 0x90, 60, 100, 0x00, 0xFF, 0x2F, 0x00],
+        ));
+        let smf = Smf::parse(&bytes).unwrap();
+        assert_messages(&smf, &[(0., &[0x90, 60, 100])]);
+    }
+
+    #[test]
+    fn truncated_last_chunk() {
+        let track = [0x00, 0x90, 60, 100, 0x83, 0x60, 0x80, 60, 0];
+        let mut bytes = header(0, 1, 480);
+        bytes.extend(chunk(b"MTrk", &track));
+        // the length claims more than there is, which is read as the rest of the file
+        let len = bytes.len() - track.len() - 4;
+        bytes[len..len + 4].copy_from_slice(&100u32.to_be_bytes());
+        let smf = Smf::parse(&bytes).unwrap();
+        assert_messages(&smf, &[(0., &[0x90, 60, 100]), (0.5, &[0x80, 60, 0])]);
+
+        // an event cut off in the middle is an error though
+        bytes.pop();
+        assert!(matches!(Smf::parse(&bytes), Err(SmfReadError::Invalid(_))));
+    }
+
+    #[test]
+    fn smpte_division() {
+        // 25 frames per second of 40 ticks each
+        let track = [0x00, 0x90, 60, 100, 0x87, 0x68, 0x80, 60, 0];
+        let smf = Smf::parse(&file(0, 0xE728, &[&track])).unwrap();
+        assert_messages(&smf, &[(0., &[0x90, 60, 100]), (1., &[0x80, 60, 0])]);
+
+        for division in [0x8028, 0xE700, 0xFF28] {
+            assert!(matches!(
+                Smf::parse(&file(0, division, &[&track])),
+                Err(SmfReadError::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn write_and_parse_round_trip() {
+        let ticks_per_second = DIVISION as f64 * 1e6 / DEFAULT_TEMPO as f64;
+        let messages = (0..200)
+            .map(|i| {
+                let time = i as f64 * 0.0123 + (i % 7) as f64 * 0.5;
+                let bytes = match i % 3 {
+                    0 => [0x90 | (i % 16) as u8, (i % 128) as u8, (i % 127 + 1) as u8],
+                    1 => [0x80 | (i % 16) as u8, (i % 128) as u8, (i * 5 % 128) as u8],
+                    _ => [0x90, (i % 128) as u8, 0],
+                };
+                (time, ShortMessage::new(&bytes))
+            })
+            .collect::<Vec<_>>();
+        let mut sorted = messages.clone();
+        sorted.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+
+        let path = std::env::temp_dir().join(format!("rsynth-test-{}.mid", std::process::id()));
+        Smf {
+            messages: sorted.clone(),
+        }
+        .write(&path)
+        .unwrap();
+        let smf = Smf::read(&path);
+        fs::remove_file(&path).ok();
+        let smf = smf.unwrap();
+
+        assert_eq!(smf.messages.len(), sorted.len());
+        for ((time, message), (expected_time, expected)) in smf.messages.iter().zip(&sorted) {
+            assert!((time - expected_time).abs() <= 1. / ticks_per_second);
+            assert_eq!(message.as_bytes(), expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn format_2_is_unsupported() {
+        assert!(matches!(
+            Smf::parse(&file(2, 480, &[&[]])),
+            Err(SmfReadError::UnsupportedFormat(2))
+        ));
+    }
+}
@@ -0,0 +1,93 @@
+use crate::frame::Frame;
+use std::any::Any;
+
+/// Per-key state of stateful oscillators.
+/// Every stateful oscillator claims a slot with `State::slot`. Slots are handed out in the order
+/// oscillators are evaluated, which is the same for every sample, so each oscillator gets its own
+/// slot back on the next sample.
+/// Slots are never freed, pressing the key again only marks them as fresh, so once every slot
+/// has been created no more allocations happen. `Patch::new` plays every key once, so they're
+/// created before the audio thread gets the patch.
+/// Known limitation: slots sized by frequency, like `Pluck`'s string, still grow on the audio
+/// thread when a key is modulated below the pitch it was primed at.
+pub struct State {
+    pub sample_rate: f64,
+    pub time_pressed: f64,
+    pub released: bool,
+    /// The current frame of the audio input, silent if there is none.
+    pub input: Frame,
+    slots: Vec<(Box<dyn Any + Send>, u64)>,
+    cursor: usize,
+    generation: u64,
+    /// States of the instrument's zones, see `State::zone`.
+    zones: Vec<State>,
+}
+
+impl State {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            sample_rate,
+            time_pressed: 0.,
+            released: false,
+            input: [0., 0.],
+            slots: Vec::new(),
+            cursor: 0,
+            generation: 0,
+            zones: Vec::new(),
+        }
+    }
+
+    /// Marks every slot as fresh, called whenever the key is pressed.
+    pub fn reset(&mut self, time_pressed: f64) {
+        self.time_pressed = time_pressed;
+        self.released = false;
+        self.cursor = 0;
+        self.generation += 1;
+        self.zones
+            .iter_mut()
+            .for_each(|zone| zone.reset(time_pressed));
+    }
+
+    /// Starts handing out slots from the beginning, called before every sample.
+    pub fn rewind(&mut self) {
+        self.cursor = 0;
+    }
+
+    /// Returns the state of the zone at `index`, creating it if it doesn't exist yet, in sync
+    /// with this one.
+    pub fn zone(&mut self, index: usize) -> &mut State {
+        while self.zones.len() <= index {
+            let mut zone = State::new(self.sample_rate);
+            zone.reset(self.time_pressed);
+            self.zones.push(zone);
+        }
+        let zone = &mut self.zones[index];
+        zone.sample_rate = self.sample_rate;
+        zone.time_pressed = self.time_pressed;
+        zone.released = self.released;
+        zone
+    }
+
+    /// Returns the next slot, creating it if it doesn't exist yet, and whether it's fresh,
+    /// meaning the key has been pressed since the slot was last handed out.
+    /// Oscillators are expected to (re)initialize fresh slots in place.
+    pub fn slot<T: Any + Send + Default>(&mut self) -> (&mut T, bool) {
+        let index = self.cursor;
+        self.cursor += 1;
+
+        if index == self.slots.len() {
+            self.slots
+                .push((Box::<T>::default(), self.generation.wrapping_sub(1)));
+        } else if !self.slots[index].0.is::<T>() {
+            self.slots[index].0 = Box::<T>::default();
+        }
+
+        let (slot, generation) = &mut self.slots[index];
+        let fresh = *generation != self.generation;
+        *generation = self.generation;
+        (
+            slot.downcast_mut().expect("slot holds a different type!"),
+            fresh,
+        )
+    }
+}
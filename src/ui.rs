@@ -1,4 +1,10 @@
-use crate::{hz::Hz, Data};
+use crate::{
+    engine::{Connection, Reporter, Status},
+    hz::Hz,
+    instrument::Instrument,
+    state::State,
+    Data,
+};
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent},
     execute,
@@ -6,15 +12,44 @@ use crossterm::{
 };
 use ratatui::{
     prelude::*,
-    widgets::{Axis, Chart, Dataset, GraphType},
+    widgets::{Axis, Chart, Dataset, GraphType, Paragraph},
 };
 use std::{
     io,
-    sync::{Arc, Mutex},
+    sync::{atomic::Ordering, Arc, Mutex},
     time::Duration,
 };
 
-pub fn run(data: Arc<Mutex<Data>>) {
+/// Points the oscillator's waveform is drawn with.
+const PREVIEW_POINTS: usize = 512;
+
+fn period() -> f64 {
+    let one_period = 1. / (2. * ::std::f64::consts::PI);
+    let num_periods = 2.;
+    num_periods * one_period
+}
+
+/// Samples two periods of an instrument's oscillator for drawing.
+pub fn preview(instrument: &Instrument) -> Vec<(f64, f64)> {
+    let period = period();
+    let ratio = 1. / PREVIEW_POINTS as f64;
+    let mut state = State::new(PREVIEW_POINTS as f64 / period);
+    (0..PREVIEW_POINTS)
+        .map(|x| {
+            let x = x as f64 * ratio * period;
+            state.rewind();
+            (
+                x,
+                instrument
+                    .oscillator
+                    .value((2. * ::std::f64::consts::PI).hz(), x, &mut state)
+                    as f64,
+            )
+        })
+        .collect()
+}
+
+pub fn run(data: Arc<Mutex<Data>>, status: Arc<Status>) {
     enable_raw_mode().expect("failed to enable raw mode!");
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen, EnableMouseCapture)
@@ -22,29 +57,42 @@ pub fn run(data: Arc<Mutex<Data>>) {
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend).expect("failed to create a terminal!");
 
-    let one_period = 1. / (2. * ::std::f64::consts::PI);
-    let num_periods = 2.;
-    let period = num_periods * one_period;
+    let period = period();
 
     let mut first_draw = true;
+    // the log goes into a file, so the problems the audio thread counts still get reported
+    let mut reporter = Reporter::default();
 
     loop {
+        reporter.report(&status);
         if !first_draw
             && event::poll(Duration::from_millis(500)).expect("io error during event poll!")
         {
             match event::read().expect("failed to read an event!") {
-                Event::Resize(..) => {
-                    data.lock().expect("failed to acquire lock!").should_redraw = true
-                }
+                Event::Resize(..) => status.should_redraw.store(true, Ordering::Relaxed),
                 Event::Key(KeyEvent {
                     code: KeyCode::Char('q'),
                     ..
                 }) => break,
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('m'),
+                    ..
+                }) => {
+                    status.record_midi.fetch_xor(true, Ordering::Relaxed);
+                    status.should_redraw.store(true, Ordering::Relaxed);
+                }
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('a'),
+                    ..
+                }) => {
+                    status.record_audio.fetch_xor(true, Ordering::Relaxed);
+                    status.should_redraw.store(true, Ordering::Relaxed);
+                }
                 _ => (),
             }
         }
 
-        if !first_draw && !data.lock().expect("failed to acquire lock!").should_redraw {
+        if !first_draw && !status.should_redraw.load(Ordering::Relaxed) {
             continue;
         }
 
@@ -52,23 +100,18 @@ pub fn run(data: Arc<Mutex<Data>>) {
             .draw(|f| {
                 let layout = Layout::new()
                     .direction(Direction::Vertical)
-                    .constraints([Constraint::Percentage(100)])
+                    .constraints([
+                        Constraint::Min(0),
+                        Constraint::Length(1),
+                        Constraint::Length(1),
+                    ])
                     .split(f.size());
                 let ratio = 1. / layout[0].width as f64;
-                let values = (0..layout[0].width)
-                    .map(|x| {
-                        let x = x as f64 * ratio * period;
-                        (
-                            x,
-                            data.lock()
-                                .expect("failed to acquire lock!")
-                                .instrument
-                                .oscillator
-                                .value((2. * ::std::f64::consts::PI).hz(), x)
-                                as f64,
-                        )
-                    })
-                    .collect::<Vec<_>>();
+                let values = data
+                    .lock()
+                    .expect("failed to acquire lock!")
+                    .preview
+                    .clone();
                 let values_zero = (0..layout[0].width)
                     .map(|x| (x as f64 * ratio * period, 0.))
                     .collect::<Vec<_>>();
@@ -97,11 +140,54 @@ pub fn run(data: Arc<Mutex<Data>>) {
                         .labels(["-1", "0", "1"].into_iter().map(Span::from).collect()),
                 );
                 f.render_widget(chart, layout[0]);
+
+                let peak = status.peak();
+                let clipped_samples = status.clipped_samples.load(Ordering::Relaxed);
+                let midi_errors = status.midi_errors.load(Ordering::Relaxed);
+                let connection = status.connection();
+                let server = Paragraph::new(format!(
+                    "jack: {} | {} Hz | {} frames | xruns: {}",
+                    match connection {
+                        Connection::Disconnected => "disconnected",
+                        Connection::Connecting => "connecting",
+                        Connection::Connected => "connected",
+                    },
+                    status.sample_rate.load(Ordering::Relaxed),
+                    status.buffer_size.load(Ordering::Relaxed),
+                    status.xruns.load(Ordering::Relaxed),
+                ));
+                f.render_widget(
+                    if connection == Connection::Connected {
+                        server
+                    } else {
+                        server.red()
+                    },
+                    layout[2],
+                );
+                let recording = [
+                    (status.record_midi.load(Ordering::Relaxed), " | recording midi"),
+                    (status.record_audio.load(Ordering::Relaxed), " | recording audio"),
+                ]
+                .into_iter()
+                .filter_map(|(recording, label)| recording.then_some(label))
+                .collect::<String>();
+                let status = Paragraph::new(format!(
+                    "peak: {:.1} dBFS | clipped samples: {clipped_samples} | midi errors: {midi_errors}{recording}",
+                    20. * peak.log10()
+                ));
+                f.render_widget(
+                    if clipped_samples > 0 || midi_errors > 0 {
+                        status.red()
+                    } else {
+                        status
+                    },
+                    layout[1],
+                );
             })
             .expect("io error during terminal draw!");
 
         first_draw = false;
-        data.lock().expect("failed to acquire lock!").should_redraw = false;
+        status.should_redraw.store(false, Ordering::Relaxed);
     }
 
     disable_raw_mode().expect("failed to disable raw mode!");
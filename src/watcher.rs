@@ -1,32 +1,65 @@
-use crate::{envelope::Envelope, instrument::Instrument, osc, Data};
+use crate::{
+    engine::{self, Patch, Status},
+    envelope::Envelope,
+    instrument::Instrument,
+    osc, ui, Data,
+};
 use notify::{
     recommended_watcher, Error, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
 };
 use std::{
-    path::Path,
-    sync::{Arc, Mutex},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::Ordering,
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
 };
 
-pub fn init(data: Arc<Mutex<Data>>, instrument_path: String) -> RecommendedWatcher {
-    let asdf = instrument_path.clone();
+/// How often the reloader checks whether the sample rate changed.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Watches the instrument of every part, `instrument_paths` being in the order of the parts.
+/// A changed instrument only reloads the parts playing it, and becomes the one the UI previews.
+/// Every part is reloaded when the sample rate changes, since patches only fit a single one.
+pub fn init(
+    data: Arc<Mutex<Data>>,
+    status: Arc<Status>,
+    patches: SyncSender<(usize, Box<Patch>)>,
+    garbage: Receiver<Box<Patch>>,
+    instrument_paths: Vec<PathBuf>,
+) -> RecommendedWatcher {
+    // events may name a file differently than the session did
+    let canonical = instrument_paths
+        .iter()
+        .map(|path| path.canonicalize().unwrap_or_else(|_| path.clone()))
+        .collect::<Vec<_>>();
+    let mut watched = instrument_paths.clone();
+    watched.sort();
+    watched.dedup();
+
+    let (changes, changed) = mpsc::channel();
+    thread::Builder::new()
+        .name("rsynth-reload".to_string())
+        .spawn(move || reload(data, status, patches, garbage, instrument_paths, changed))
+        .expect("failed to spawn reload thread!");
+
     let mut watcher = recommended_watcher(move |x: Result<Event, Error>| {
         match x {
             Ok(ev) => match ev.kind {
                 EventKind::Modify(_) => {
-                    let _ = std::mem::replace(
-                        &mut data.lock().expect("failed to acquire lock!").instrument,
-                        Instrument::read(&instrument_path).unwrap_or(Instrument {
-                            volume: 1.,
-                            envelope: Envelope::ADSR {
-                                attack_time: 0.,
-                                decay_time: 0.,
-                                sustain_amplitude: 0.,
-                                release_time: 0.,
-                            },
-                            oscillator: Box::new(osc::Sawtooth { num_sinewaves: 0 }),
-                        }),
-                    );
-                    data.lock().expect("failed to acquire lock!").should_redraw = true;
+                    let changed = |path: &Path| {
+                        ev.paths.iter().any(|changed| {
+                            changed == path || changed.canonicalize().is_ok_and(|c| c == path)
+                        })
+                    };
+                    for (part, path) in canonical.iter().enumerate() {
+                        if changed(path) {
+                            changes.send(part).ok();
+                        }
+                    }
                 }
                 _ => (),
             },
@@ -35,9 +68,77 @@ pub fn init(data: Arc<Mutex<Data>>, instrument_path: String) -> RecommendedWatch
     })
     .expect("failed to create watcher!");
 
-    watcher
-        .watch(Path::new(&asdf), RecursiveMode::NonRecursive)
-        .expect("watcher failed! (?)");
+    for path in watched {
+        watcher
+            .watch(&path, RecursiveMode::NonRecursive)
+            .expect("watcher failed! (?)");
+    }
 
     watcher
 }
+
+/// Builds and sends the patches of the parts that arrive through `changed`, and of every part
+/// whenever the sample rate changes, queued patches for the old rate included.
+/// Returns once the watcher is dropped.
+fn reload(
+    data: Arc<Mutex<Data>>,
+    status: Arc<Status>,
+    patches: SyncSender<(usize, Box<Patch>)>,
+    garbage: Receiver<Box<Patch>>,
+    instrument_paths: Vec<PathBuf>,
+    changed: Receiver<usize>,
+) {
+    let mut sample_rate = status.sample_rate.load(Ordering::Acquire);
+    loop {
+        let mut parts = match changed.recv_timeout(POLL_INTERVAL) {
+            Ok(part) => vec![part],
+            Err(RecvTimeoutError::Timeout) => Vec::new(),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        parts.extend(changed.try_iter());
+        let previewed = parts.last().copied();
+        let current = status.sample_rate.load(Ordering::Acquire);
+        if current != sample_rate {
+            sample_rate = current;
+            parts = (0..instrument_paths.len()).collect();
+        }
+        parts.sort();
+        parts.dedup();
+
+        for &part in &parts {
+            let instrument = read(&instrument_paths[part]);
+            if previewed == Some(part) {
+                data.lock().expect("failed to acquire lock!").preview = ui::preview(&instrument);
+            }
+            engine::send_patch(
+                part,
+                Patch::new(instrument, sample_rate as f64),
+                &patches,
+                &garbage,
+            );
+        }
+        if !parts.is_empty() {
+            status.should_redraw.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Reads an instrument, falling back to a silent one while it doesn't parse.
+fn read(path: &Path) -> Instrument {
+    Instrument::read(path).unwrap_or(Instrument {
+        volume: 1.,
+        pan: 0.,
+        envelope: Envelope::ADSR {
+            attack_time: 0.,
+            decay_time: 0.,
+            sustain_amplitude: 0.,
+            release_time: 0.,
+        },
+        oscillator: Box::new(osc::Sawtooth { num_sinewaves: 0 }),
+        key_follow: Default::default(),
+        input_level: 0.,
+        effects: Vec::new(),
+        master: Default::default(),
+        zones: Vec::new(),
+    })
+}
@@ -0,0 +1,110 @@
+use crate::{envelope::Envelope, osc::Oscillator};
+use serde::{Deserialize, Serialize};
+
+/// A sub-instrument played on a range of keys and velocities, both inclusive.
+/// Without an `envelope` the zone uses the instrument's.
+/// `key_fade` and `velocity_fade` fade the zone in and out over that many keys or velocities
+/// at the inner edges of its ranges, so two zones overlapping by that much crossfade evenly.
+#[derive(Deserialize, Serialize)]
+pub struct Zone {
+    #[serde(default = "full_range")]
+    pub keys: (u8, u8),
+    #[serde(default = "full_range")]
+    pub velocities: (u8, u8),
+    #[serde(default)]
+    pub key_fade: u8,
+    #[serde(default)]
+    pub velocity_fade: u8,
+    #[serde(default = "full_volume")]
+    pub volume: f32,
+    #[serde(default)]
+    pub envelope: Option<Envelope>,
+    pub oscillator: Box<dyn Oscillator>,
+}
+
+fn full_range() -> (u8, u8) {
+    (0, 127)
+}
+
+fn full_volume() -> f32 {
+    1.
+}
+
+/// Gain of `value` within `range`, fading over `fade` steps at the edges that aren't the
+/// lowest or highest possible value, `lowest` being 0 for keys and 1 for velocities.
+fn fade(value: u8, (low, high): (u8, u8), fade: u8, lowest: u8) -> f64 {
+    if value < low || value > high {
+        return 0.;
+    }
+    if fade == 0 {
+        return 1.;
+    }
+    let fade = fade as f64;
+    let fade_in = if low <= lowest {
+        1.
+    } else {
+        (value as f64 - low as f64 + 0.5) / fade
+    };
+    let fade_out = if high >= 127 {
+        1.
+    } else {
+        (high as f64 - value as f64 + 0.5) / fade
+    };
+    fade_in.min(fade_out).min(1.)
+}
+
+impl Zone {
+    /// Gain of the zone for a key pressed at a velocity, zero if it's outside of the zone.
+    pub fn gain(&self, key_number: usize, velocity: u8) -> f64 {
+        let key_number = key_number.min(u8::MAX as usize) as u8;
+        // a velocity of 0 is a note off, so 1 is the lowest one played
+        fade(key_number, self.keys, self.key_fade, 0)
+            * fade(velocity, self.velocities, self.velocity_fade, 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osc::Sawtooth;
+
+    fn zone(keys: (u8, u8), velocities: (u8, u8), fade: u8) -> Zone {
+        Zone {
+            keys,
+            velocities,
+            key_fade: fade,
+            velocity_fade: fade,
+            volume: 1.,
+            envelope: None,
+            oscillator: Box::new(Sawtooth { num_sinewaves: 0 }),
+        }
+    }
+
+    #[test]
+    fn crossfade_sums_to_one() {
+        let low = zone((0, 64), (1, 127), 4);
+        let high = zone((61, 127), (1, 127), 4);
+        for key in 0..128 {
+            let sum = low.gain(key, 100) + high.gain(key, 100);
+            assert!((sum - 1.).abs() < 1e-9, "key {key}: {sum}");
+        }
+
+        let soft = zone((0, 127), (1, 80), 8);
+        let loud = zone((0, 127), (73, 127), 8);
+        for velocity in 1..128 {
+            let sum = soft.gain(60, velocity) + loud.gain(60, velocity);
+            assert!((sum - 1.).abs() < 1e-9, "velocity {velocity}: {sum}");
+        }
+    }
+
+    #[test]
+    fn outer_edges_dont_fade() {
+        let inner = zone((1, 126), (2, 126), 4);
+        // key 1 and velocity 2 aren't the lowest possible values, so they fade in
+        assert!(inner.gain(1, 64) < 1.);
+        assert!(inner.gain(64, 2) < 1.);
+        let full = zone((0, 127), (1, 127), 4);
+        assert_eq!(full.gain(0, 1), 1.);
+        assert_eq!(full.gain(127, 127), 1.);
+    }
+}